@@ -1,5 +1,6 @@
 //! A simple argv parser
 
+use crate::time;
 use std::{
     collections::HashMap,
     env,
@@ -13,8 +14,18 @@ pub enum MessageKind {
     Plaintext,
     /// A markdown message
     Markdown,
+    /// A notice, i.e. a message that does not trigger notifications
+    Notice,
+    /// An emote, i.e. a `/me`-style action
+    Emote,
+    /// A pre-rendered HTML message
+    Html,
     /// A raw message/attachment
     Raw,
+    /// A reaction to a previously sent message
+    Reaction,
+    /// A redaction of a previously sent message
+    Redaction,
 }
 impl TryFrom<String> for MessageKind {
     type Error = Error;
@@ -23,6 +34,9 @@ impl TryFrom<String> for MessageKind {
         match value.as_str() {
             "plaintext" | "text" => Ok(MessageKind::Plaintext),
             "markdown" => Ok(MessageKind::Markdown),
+            "notice" => Ok(MessageKind::Notice),
+            "emote" => Ok(MessageKind::Emote),
+            "html" => Ok(MessageKind::Html),
             "raw" => Ok(MessageKind::Raw),
             _ => Err(Error::from(ErrorKind::InvalidInput)),
         }
@@ -36,37 +50,275 @@ pub struct Argv {
     pub ipc_path: String,
     /// The message kind
     pub kind: MessageKind,
-    /// The message payload
+    /// The message payload; the reaction or the redaction reason for reactions and redactions
     pub payload: String,
+    /// The event key of the message to react to or to redact
+    pub target: Option<String>,
+    /// An optional plaintext fallback for HTML messages
+    pub fallback: Option<String>,
+    /// An optional key to deduplicate messages with varying contents
+    pub dedup_key: Option<String>,
+    /// Whether the message should be held back for the next digest
+    pub digest: Option<bool>,
+    /// An optional source tag which is used to group messages in a digest
+    pub tag: Option<String>,
+    /// The UNIX timestamp before which the message must not be sent
+    pub not_before: Option<u64>,
+    /// The UNIX timestamp after which the message is stale and must not be sent anymore
+    pub expires: Option<u64>,
+    /// The message priority, i.e. `low`, `normal`, `high` or `critical`
+    pub priority: Option<String>,
+    /// An optional key to record the event ID of the sent message under
+    pub event_key: Option<String>,
+    /// The key of a previously sent message to reply to
+    pub reply_to: Option<String>,
+    /// The key of a thread to send the message into; an unknown key starts a new thread
+    pub thread: Option<String>,
+    /// The key of a previously sent message to edit; an unknown key sends a new message
+    pub edit: Option<String>,
+    /// The mentioned user IDs or server-side aliases
+    pub mentions: Vec<String>,
+    /// Whether the message pings the entire room via `@room`
+    pub ping_room: bool,
+    /// The paths of the files that are sent in order after a text message
+    pub files: Vec<String>,
+    /// Whether the files are threaded under the text message
+    pub thread_files: bool,
+    /// The producer name to sign the message as
+    pub producer: Option<String>,
+    /// The path to the file with the hex-encoded producer key
+    pub key_file: Option<String>,
+    /// The path to the file with the hex-encoded public key of the server to seal the message to
+    pub seal_key: Option<String>,
 }
 impl Argv {
     /// The valid argument keys
-    const VALID_KEYS: &[&'static str] = &["ipc-path", "type", "payload"];
+    const VALID_KEYS: &[&'static str] = &[
+        "ipc-path",
+        "type",
+        "payload",
+        "fallback",
+        "dedup-key",
+        "digest",
+        "tag",
+        "at",
+        "delay",
+        "ttl",
+        "priority",
+        "event-key",
+        "reply-to",
+        "thread",
+        "edit",
+        "target",
+        "reaction",
+        "reason",
+        "mention",
+        "ping-room",
+        "file",
+        "thread-files",
+        "producer",
+        "key-file",
+        "seal-key",
+    ];
+    /// The argument keys that can be specified multiple times
+    const REPEATABLE_KEYS: &[&'static str] = &["mention", "file"];
+    /// The separator for the values of repeatable keys
+    ///
+    /// # Note
+    /// A NUL byte cannot occur within a command line argument, so that values such as file paths are kept intact
+    const REPEATABLE_SEPARATOR: char = '\0';
+    /// The argument keys that can be specified without value as shorthand for `=true`
+    const FLAG_KEYS: &[&'static str] = &["ping-room", "thread-files"];
 
     /// Loads the argv and predigests them
+    ///
+    /// # Subcommands
+    /// If the first argument is `react` or `redact`, a reaction to or a redaction of the message with the given
+    /// `--target` key is sent instead of a new message
     pub fn load() -> Result<Self, Error> {
-        // Ingest argv
-        let mut argv = Self::ingest_argv()?;
+        // Split the subcommand if any and ingest argv
+        let mut args = env::args().skip(1).peekable();
+        let subcommand = args.next_if(|arg| !arg.starts_with("--"));
+        let mut argv = Self::ingest_argv(args)?;
 
         // Get the raw argument values or choose a default value
         let ipc_path = argv.remove("ipc-path").unwrap_or_else(|| String::from("/var/run/sendmatrix"));
-        let type_ = argv.remove("type").unwrap_or_else(|| String::from("plaintext"));
-        let payload = argv.remove("payload").unwrap_or_else(|| String::from("-"));
+        let type_ = argv.remove("type");
+        let payload = argv.remove("payload");
+        let target = argv.remove("target");
+        let reaction = argv.remove("reaction");
+        let reason = argv.remove("reason");
+        let fallback = argv.remove("fallback");
+        let dedup_key = argv.remove("dedup-key");
+        let digest = argv.remove("digest");
+        let tag = argv.remove("tag");
+        let at = argv.remove("at");
+        let delay = argv.remove("delay");
+        let ttl = argv.remove("ttl");
+        let priority = argv.remove("priority");
+        let event_key = argv.remove("event-key");
+        let reply_to = argv.remove("reply-to");
+        let thread = argv.remove("thread");
+        let edit = argv.remove("edit");
+        let mentions = argv.remove("mention");
+        let ping_room = argv.remove("ping-room");
+        let files = argv.remove("file");
+        let thread_files = argv.remove("thread-files");
+        let producer = argv.remove("producer");
+        let key_file = argv.remove("key-file");
+        let seal_key = argv.remove("seal-key");
+
+        // Parse the values
+        let kind = match (subcommand.as_deref(), type_) {
+            (None, Some(type_)) => MessageKind::try_from(type_)?,
+            (None, None) => MessageKind::Plaintext,
+            (Some("react"), None) => MessageKind::Reaction,
+            (Some("redact"), None) => MessageKind::Redaction,
+            (Some(subcommand), _) => {
+                eprintln!("!> Invalid subcommand: {subcommand}");
+                return Err(Error::from(ErrorKind::InvalidInput));
+            }
+        };
+        let payload = match (kind, payload, reaction, reason) {
+            (MessageKind::Reaction, None, Some(reaction), None) => reaction,
+            (MessageKind::Redaction, None, None, reason) => reason.unwrap_or_default(),
+            (MessageKind::Reaction | MessageKind::Redaction, _, _, _) => {
+                eprintln!("!> Reactions require a reaction and redactions take an optional reason, but no payload");
+                return Err(Error::from(ErrorKind::InvalidInput));
+            }
+            (_, payload, None, None) => payload.unwrap_or_else(|| String::from("-")),
+            (_, _, _, _) => {
+                eprintln!("!> Reaction and reason are only supported for reactions and redactions");
+                return Err(Error::from(ErrorKind::InvalidInput));
+            }
+        };
+        let mentions: Vec<String> = mentions
+            .iter()
+            .flat_map(|mentions| mentions.split([Self::REPEATABLE_SEPARATOR, ',']))
+            .map(str::to_string)
+            .collect();
+        if let Some(mention) =
+            mentions.iter().find(|mention| mention.is_empty() || mention.contains(char::is_whitespace))
+        {
+            eprintln!(r#"!> Invalid mention: "{mention}""#);
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        let ping_room = match ping_room.as_deref().map(str::parse) {
+            Some(Ok(ping_room)) => ping_room,
+            Some(Err(e)) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+            None => false,
+        };
+        let files: Vec<String> =
+            files.iter().flat_map(|files| files.split(Self::REPEATABLE_SEPARATOR)).map(str::to_string).collect();
+        let thread_files = match thread_files.as_deref().map(str::parse) {
+            Some(Ok(thread_files)) => thread_files,
+            Some(Err(e)) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+            None => false,
+        };
+        let is_text = !matches!(kind, MessageKind::Raw | MessageKind::Reaction | MessageKind::Redaction);
+        if !files.is_empty() && !is_text {
+            eprintln!("!> Files are only supported for text messages");
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        if thread_files && files.is_empty() {
+            eprintln!("!> Threading files requires at least one file");
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        let is_update = matches!(kind, MessageKind::Reaction | MessageKind::Redaction);
+        if is_update != target.is_some() {
+            eprintln!("!> Reactions and redactions require a target, other messages don't support one");
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        if fallback.is_some() && kind != MessageKind::Html {
+            eprintln!("!> Fallback is only supported for HTML messages");
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        let digest = match digest.as_deref().map(str::parse) {
+            Some(Ok(digest)) => Some(digest),
+            Some(Err(e)) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+            None => None,
+        };
+        let not_before = match (at, delay) {
+            (Some(at), None) => Some(time::parse_at(&at)?),
+            (None, Some(delay)) => Some(time::now().saturating_add(time::parse_delay(&delay)?)),
+            (None, None) => None,
+            (Some(_), Some(_)) => {
+                eprintln!("!> Conflicting keys: at, delay");
+                return Err(Error::from(ErrorKind::InvalidInput));
+            }
+        };
+
+        let expires = match ttl {
+            Some(ttl) => Some(not_before.unwrap_or_else(time::now).saturating_add(time::parse_delay(&ttl)?)),
+            None => None,
+        };
+        if let Some(priority) = &priority {
+            let true = ["low", "normal", "high", "critical"].contains(&priority.as_str()) else {
+                eprintln!("!> Invalid priority: {priority}");
+                return Err(Error::from(ErrorKind::InvalidInput));
+            };
+        }
+
+        if [&reply_to, &thread, &edit].into_iter().flatten().count() > 1 {
+            eprintln!("!> Conflicting keys: reply-to, thread, edit");
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        if producer.is_some() != key_file.is_some() {
+            eprintln!("!> Signing requires both a producer and a key file");
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        if let Some(producer) =
+            producer.as_ref().filter(|producer| producer.is_empty() || producer.contains(char::is_whitespace))
+        {
+            eprintln!(r#"!> Invalid producer: "{producer}""#);
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
 
         // Init self
-        let kind = MessageKind::try_from(type_)?;
-        Ok(Self { ipc_path, kind, payload })
+        Ok(Self {
+            ipc_path,
+            kind,
+            payload,
+            target,
+            fallback,
+            dedup_key,
+            digest,
+            tag,
+            not_before,
+            expires,
+            priority,
+            event_key,
+            reply_to,
+            thread,
+            edit,
+            mentions,
+            ping_room,
+            files,
+            thread_files,
+            producer,
+            key_file,
+            seal_key,
+        })
     }
 
     /// Loads all valid argv into a key-value map
-    fn ingest_argv() -> Result<HashMap<String, String>, Error> {
+    fn ingest_argv<I>(args: I) -> Result<HashMap<String, String>, Error>
+    where
+        I: Iterator<Item = String>,
+    {
         // Parse all arguments as key-value pairs
-        let mut argv = HashMap::new();
-        for arg in env::args().skip(1) {
-            // Split the argument into key-value
-            let Some((key, value)) = arg.split_once('=') else {
-                eprintln!("!> Invalid argument: {arg}");
-                return Err(Error::from(ErrorKind::InvalidInput));
+        let mut argv: HashMap<String, String> = HashMap::new();
+        for arg in args {
+            // Split the argument into key-value; flags are shorthands for `=true`
+            let (key, value) = match arg.split_once('=') {
+                Some((key, value)) => (key, value),
+                None if Self::FLAG_KEYS.iter().any(|flag| arg.strip_prefix("--") == Some(flag)) => {
+                    (arg.as_str(), "true")
+                }
+                None => {
+                    eprintln!("!> Invalid argument: {arg}");
+                    return Err(Error::from(ErrorKind::InvalidInput));
+                }
             };
 
             // Remove the "--"-argument prefix
@@ -81,6 +333,13 @@ impl Argv {
                 return Err(Error::from(ErrorKind::InvalidInput));
             };
 
+            // Collect the values of repeatable keys as separated list
+            if let (true, Some(values)) = (Self::REPEATABLE_KEYS.contains(&key), argv.get_mut(key)) {
+                values.push(Self::REPEATABLE_SEPARATOR);
+                values.push_str(value);
+                continue;
+            }
+
             // Register pair
             let None = argv.insert(key.to_string(), value.to_string()) else {
                 eprintln!("!> Duplicated key: {key}");
@@ -0,0 +1,49 @@
+//! Signing of IPC messages with a producer key
+
+use hmac::{Hmac, Mac};
+use sendmatrix_common::hex::decode_hex;
+use sha2::Sha256;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Error, ErrorKind, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// The key of the signature header line
+const SIGNATURE_KEY: &str = "signature=";
+/// The placeholder for the hex-encoded HMAC-SHA256 which is overwritten once the message has been written
+const SIGNATURE_PLACEHOLDER: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Creates the placeholder signature line which must be the first header line of a signed message
+pub fn placeholder() -> String {
+    format!("{SIGNATURE_KEY}{SIGNATURE_PLACEHOLDER}\n")
+}
+
+/// Loads a hex-encoded producer key from a file
+pub fn load_key(path: &str) -> Result<Vec<u8>, Error> {
+    let key = fs::read_to_string(path)?;
+    match decode_hex(key.trim()) {
+        Some(key) if !key.is_empty() => Ok(key),
+        _ => Err(Error::new(ErrorKind::InvalidData, "invalid producer key")),
+    }
+}
+
+/// Signs a written message by replacing the placeholder signature with the HMAC-SHA256 over everything except the
+/// signature line
+///
+/// # Important
+/// The message must start with the line created by [`placeholder`]
+pub fn sign(path: &Path, key: &[u8]) -> Result<(), Error> {
+    // Compute the MAC over everything after the signature line
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    file.seek(SeekFrom::Start(placeholder().len() as u64))?;
+    let mut hmac = Hmac::<Sha256>::new_from_slice(key).map_err(Error::other)?;
+    io::copy(&mut file, &mut hmac)?;
+    let mac = hmac.finalize().into_bytes();
+
+    // Replace the placeholder
+    let mac: String = mac.iter().map(|byte| format!("{byte:02x}")).collect();
+    file.seek(SeekFrom::Start(SIGNATURE_KEY.len() as u64))?;
+    file.write_all(mac.as_bytes())?;
+    file.sync_all()
+}
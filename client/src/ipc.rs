@@ -1,10 +1,15 @@
 //! The IPC server
 
-use crate::argv::MessageKind;
+use crate::{
+    argv::{Argv, MessageKind},
+    auth, seal, time,
+};
+use sendmatrix_common::filename;
 use std::{
-    fs,
-    io::{self, Error, ErrorKind, Read},
-    path::Path,
+    env,
+    fs::{self, File},
+    io::{self, Error, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
 };
 
 /// The IPC adapter
@@ -14,16 +19,22 @@ pub struct Ipc {
 }
 impl Ipc {
     /// Sends a message
-    pub fn send(ipc_path: &str, kind: MessageKind, payload: String) -> Result<(), Error> {
-        match kind {
-            MessageKind::Plaintext | MessageKind::Markdown => Self::sendtext(ipc_path, kind, payload),
-            MessageKind::Raw => Self::sendraw(ipc_path, payload),
+    pub fn send(argv: &Argv) -> Result<(), Error> {
+        match argv.kind {
+            MessageKind::Plaintext
+            | MessageKind::Markdown
+            | MessageKind::Notice
+            | MessageKind::Emote
+            | MessageKind::Html => Self::sendtext(argv),
+            MessageKind::Raw => Self::sendraw(argv),
+            MessageKind::Reaction | MessageKind::Redaction => Self::sendupdate(argv),
         }
     }
 
     /// Sends a text message
-    fn sendtext(ipc_path: &str, kind: MessageKind, mut payload: String) -> Result<(), Error> {
+    fn sendtext(argv: &Argv) -> Result<(), Error> {
         // Get the payload
+        let mut payload = argv.payload.clone();
         if payload == "-" {
             // Get stdin
             let mut stdin = io::stdin();
@@ -37,33 +48,71 @@ impl Ipc {
             }
         }
 
-        // Write the message to a tempfile
-        let uuidname = format!("{}.tmp", Self::uuidgen());
-        let tmp = Path::new(ipc_path).join(uuidname);
-        fs::write(&tmp, payload)?;
-
-        // Create the final name
-        let mut dest = tmp.clone();
-        match kind {
-            MessageKind::Plaintext => dest.set_extension("txt"),
-            MessageKind::Markdown => dest.set_extension("markdown"),
+        // Create the header
+        let type_ = match argv.kind {
+            MessageKind::Plaintext => "plaintext",
+            MessageKind::Markdown => "markdown",
+            MessageKind::Notice => "notice",
+            MessageKind::Emote => "emote",
+            MessageKind::Html => "html",
             _ => {
                 // Note: `sendtext` should not be called for not text-messages
-                #[allow(clippy::unreachable)]
+                #[allow(clippy::unreachable, reason = "see note")]
                 (unreachable!("`sendtext` should not be called for not text-messages"));
             }
         };
 
+        // Open the files that follow the message and declare them in order
+        let mut attachments = Vec::new();
+        for path in &argv.files {
+            let filename = Self::filename(Path::new(path))?;
+            let source = File::open(path)?;
+            let len = source.metadata()?.len();
+            attachments.push((format!("{len} {filename}"), len, source));
+        }
+        let mut fields = vec![("type", type_)];
+        fields.extend(attachments.iter().map(|(attachment, _, _)| ("attachment", attachment.as_str())));
+        let header = Self::header(argv, &fields)?;
+
+        // Write the message to a tempfile
+        let (tmp, mut file) = Self::tempfile(argv)?;
+        file.write_all(&header)?;
+        file.write_all(payload.as_bytes())?;
+
+        // Append the files with exactly their declared length
+        for (_, len, source) in attachments {
+            let copied = io::copy(&mut source.take(len), &mut file)?;
+            let true = copied == len else {
+                eprintln!("!> File has been truncated while sending");
+                fs::remove_file(&tmp)?;
+                return Err(Error::from(ErrorKind::UnexpectedEof));
+            };
+        }
+
         // Make file persistent
-        fs::hard_link(&tmp, dest)?;
-        fs::remove_file(tmp)?;
-        Ok(())
+        Self::publish(argv, tmp)
     }
 
     /// Sends a raw file message
-    fn sendraw(ipc_path: &str, payload: String) -> Result<(), Error> {
+    fn sendraw(argv: &Argv) -> Result<(), Error> {
+        // Create the header
+        let path = Path::new(&argv.payload);
+        let filename = Self::filename(path)?;
+        let header = Self::header(argv, &[("type", "raw"), ("name", &filename)])?;
+
+        // Copy the file to a tempfile
+        let mut source = File::open(path)?;
+        let (tmp, mut file) = Self::tempfile(argv)?;
+        file.write_all(&header)?;
+        io::copy(&mut source, &mut file)?;
+
+        // Make the file permanent
+        Self::publish(argv, tmp)
+    }
+
+    /// Gets the sanitized attachment name of a file
+    fn filename(path: &Path) -> Result<String, Error> {
         // Get the file name
-        let path = Path::new(&payload);
         let Some(filename) = path.file_name() else {
             eprintln!(r#"!> Invalid file path: "{}""#, path.display());
             return Err(Error::from(ErrorKind::InvalidInput));
@@ -75,24 +124,161 @@ impl Ipc {
             return Err(Error::from(ErrorKind::InvalidInput));
         };
 
-        // Ensure that the filename is ascii, otherwise the sendmatrix server will not process it
-        let true = filename.is_ascii() else {
-            eprintln!(r#"!> Invalid filename: "{}""#, path.display());
-            return Err(Error::from(ErrorKind::InvalidInput));
+        // Sanitize the filename, otherwise the sendmatrix server will not process it
+        // Note: Non-ascii names are fine since the name is transferred within the UTF-8 envelope header
+        match filename::sanitize(filename) {
+            Ok(filename) => Ok(filename),
+            Err(e) => {
+                eprintln!(r#"!> Invalid filename: "{}" ({e})"#, path.display());
+                Err(Error::from(ErrorKind::InvalidInput))
+            }
+        }
+    }
+
+    /// Sends a reaction to or a redaction of a previously sent message
+    fn sendupdate(argv: &Argv) -> Result<(), Error> {
+        // Create the header
+        let type_ = match argv.kind {
+            MessageKind::Reaction => "reaction",
+            _ => "redaction",
         };
+        let target = argv.target.as_deref().unwrap_or_default();
+        let header = Self::header(argv, &[("type", type_), ("target", target)])?;
 
-        // Copy the file to a tempfile
-        let tmpname = format!("{filename}.tmp");
-        let tmp = Path::new(ipc_path).join(tmpname);
-        fs::copy(payload, &tmp)?;
+        // Write the message to a tempfile
+        let (tmp, mut file) = Self::tempfile(argv)?;
+        file.write_all(&header)?;
+        file.write_all(argv.payload.as_bytes())?;
 
-        // Create the final name
-        let mut dest = tmp.clone();
-        dest.set_extension("raw");
+        // Make file persistent
+        Self::publish(argv, tmp)
+    }
 
-        // Make the file permanent
-        fs::hard_link(&tmp, dest)?;
+    /// Serializes the envelope header from the given fields and the optional argv fields
+    fn header(argv: &Argv, fields: &[(&str, &str)]) -> Result<Vec<u8>, Error> {
+        // Collect all present fields
+        let digest = argv.digest.map(|digest| if digest { "true" } else { "false" });
+        let not_before = argv.not_before.map(|not_before| not_before.to_string());
+        let expires = argv.expires.map(|expires| expires.to_string());
+        let mentions = argv.mentions.join(",");
+        let mentions = Some(mentions.as_str()).filter(|mentions| !mentions.is_empty());
+        let ping_room = argv.ping_room.then_some("true");
+        let thread_files = argv.thread_files.then_some("true");
+        // Note: Signed messages carry their signing time and a unique nonce, so that the server can reject replays
+        let signed_at = argv.producer.as_ref().map(|_| time::now().to_string());
+        let nonce = argv.producer.as_ref().map(|_| Self::uuidgen());
+        let optional = [
+            ("fallback", argv.fallback.as_deref()),
+            ("dedup-key", argv.dedup_key.as_deref()),
+            ("digest", digest),
+            ("tag", argv.tag.as_deref()),
+            ("not-before", not_before.as_deref()),
+            ("expires", expires.as_deref()),
+            ("priority", argv.priority.as_deref()),
+            ("event-key", argv.event_key.as_deref()),
+            ("reply-to", argv.reply_to.as_deref()),
+            ("thread", argv.thread.as_deref()),
+            ("edit", argv.edit.as_deref()),
+            ("mentions", mentions),
+            ("ping-room", ping_room),
+            ("thread-files", thread_files),
+            ("producer", argv.producer.as_deref()),
+            ("signed-at", signed_at.as_deref()),
+            ("nonce", nonce.as_deref()),
+        ];
+        let optional = optional.into_iter().filter_map(|(key, value)| Some((key, value?)));
+
+        // Serialize the fields; the signature placeholder of signed messages must be the first line
+        let mut header = match argv.producer {
+            Some(_) => auth::placeholder(),
+            None => String::new(),
+        };
+        for (key, value) in fields.iter().copied().chain(optional) {
+            // Ensure that the value does not break the line-based header
+            if value.contains(['\n', '\r']) {
+                eprintln!(r#"!> Invalid value for "{key}": "{}""#, value.escape_debug());
+                return Err(Error::from(ErrorKind::InvalidInput));
+            }
+            header.push_str(&format!("{key}={value}\n"));
+        }
+
+        // Terminate the header
+        header.push('\n');
+        Ok(header.into_bytes())
+    }
+
+    /// Creates the tempfile for a new message
+    ///
+    /// # Note
+    /// Messages that are sealed are written to a private file in the system's temp directory, so that their plaintext
+    /// never touches the IPC directory
+    fn tempfile(argv: &Argv) -> Result<(PathBuf, File), Error> {
+        let uuidname = format!("{}.tmp", Self::uuidgen());
+        match argv.seal_key {
+            Some(_) => {
+                let tmp = env::temp_dir().join(uuidname);
+                let file = Self::create_private(&tmp)?;
+                Ok((tmp, file))
+            }
+            None => {
+                let tmp = Path::new(&argv.ipc_path).join(uuidname);
+                let file = File::create(&tmp)?;
+                Ok((tmp, file))
+            }
+        }
+    }
+
+    /// Creates a new file that is only accessible by the current user
+    #[cfg(unix)]
+    fn create_private(path: &Path) -> Result<File, Error> {
+        use std::os::unix::fs::OpenOptionsExt;
+        File::options().write(true).create_new(true).mode(0o600).open(path)
+    }
+    /// Creates a new file that is only accessible by the current user
+    ///
+    /// # Note
+    /// File modes are only supported on unix, so the file gets the default permissions on other platforms
+    #[cfg(not(unix))]
+    fn create_private(path: &Path) -> Result<File, Error> {
+        File::options().write(true).create_new(true).open(path)
+    }
+
+    /// Signs and seals a tempfile if configured and publishes it under its final `.msg` or `.sealed` name
+    fn publish(argv: &Argv, tmp: PathBuf) -> Result<(), Error> {
+        // Sign the file
+        if let Some(key_file) = &argv.key_file {
+            if let Err(e) = auth::load_key(key_file).and_then(|key| auth::sign(&tmp, &key)) {
+                fs::remove_file(&tmp)?;
+                return Err(e);
+            }
+        }
+
+        // Seal the file into a tempfile within the IPC directory and remove the plaintext
+        let (tmp, extension) = match &argv.seal_key {
+            Some(seal_key) => {
+                let sealed = Path::new(&argv.ipc_path).join(tmp.file_name().unwrap_or_default());
+                let result = seal::load_key(seal_key).and_then(|key| seal::seal(&tmp, &sealed, &key));
+                fs::remove_file(&tmp)?;
+                if let Err(e) = result {
+                    match fs::remove_file(&sealed) {
+                        Err(remove_error) if remove_error.kind() != ErrorKind::NotFound => return Err(remove_error),
+                        _ => return Err(e),
+                    }
+                }
+                (sealed, "sealed")
+            }
+            None => (tmp, "msg"),
+        };
+
+        // Link the file to its final name
+        let dest = tmp.with_extension(extension);
+        fs::hard_link(&tmp, &dest)?;
         fs::remove_file(tmp)?;
+
+        // Print the path of scheduled messages, so that they can be cancelled by deleting the file
+        if argv.not_before.is_some() {
+            println!("{}", dest.display());
+        }
         Ok(())
     }
 
@@ -101,7 +287,7 @@ impl Ipc {
         // Generate 16 random bytes
         let mut bytes = [0; 16];
         // Note: If getrandom does not work we want to terminate
-        #[allow(clippy::expect_used)]
+        #[allow(clippy::expect_used, reason = "see note")]
         getrandom::getrandom(&mut bytes).expect("failed to generate UUID");
 
         // Format UUID
@@ -14,17 +14,20 @@
 #![warn(clippy::cognitive_complexity)]
 
 mod argv;
+mod auth;
 mod ipc;
+mod seal;
+mod time;
 
 use crate::argv::Argv;
 use ipc::Ipc;
 
 fn main() {
     // Note: If the argv-parsing fails, we want to terminate
-    #[allow(clippy::expect_used)]
-    let Argv { ipc_path, kind, payload } = Argv::load().expect("failed to parse argv");
+    #[allow(clippy::expect_used, reason = "see note")]
+    let argv = Argv::load().expect("failed to parse argv");
 
     // Note: If the IPC message sending fails, we want to terminate
-    #[allow(clippy::expect_used)]
-    Ipc::send(&ipc_path, kind, payload).expect("failed to send IPC message");
+    #[allow(clippy::expect_used, reason = "see note")]
+    Ipc::send(&argv).expect("failed to send IPC message");
 }
@@ -0,0 +1,90 @@
+//! Encryption of IPC messages to the public key of the server
+//!
+//! # Format
+//! A sealed message consists of the magic bytes [`MAGIC`], the 32 byte ephemeral X25519 public key of the client and
+//! the encrypted envelope. The envelope is split into chunks of [`CHUNK_SIZE`] bytes, which are encrypted with
+//! ChaCha20-Poly1305 individually; the last chunk may be shorter. The key is derived from the X25519 shared secret via
+//! HKDF-SHA256 with the ephemeral and the server public key as salt. The nonce of a chunk is its 88 bit big-endian
+//! index, followed by `0x01` for the last chunk or `0x00` for all other chunks, so that chunks cannot be reordered or
+//! truncated.
+
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use hkdf::Hkdf;
+use sendmatrix_common::hex::decode_hex;
+use sha2::Sha256;
+use std::{
+    fs::{self, File},
+    io::{Error, ErrorKind, Read, Write},
+    path::Path,
+};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// The magic bytes of a sealed message
+const MAGIC: &[u8; 8] = b"SMSEAL01";
+/// The size of a plaintext chunk
+const CHUNK_SIZE: usize = 64 * 1024;
+/// The HKDF info to derive the message key
+const INFO: &[u8] = b"sendmatrix sealed message";
+
+/// Loads the hex-encoded public key of the server from a file
+pub fn load_key(path: &str) -> Result<[u8; 32], Error> {
+    let key = fs::read_to_string(path)?;
+    match decode_hex(key.trim()).map(<[u8; 32]>::try_from) {
+        Some(Ok(key)) => Ok(key),
+        _ => Err(Error::new(ErrorKind::InvalidData, "invalid seal key")),
+    }
+}
+
+/// Encrypts a written message to the public key of the server
+pub fn seal(source: &Path, dest: &Path, server: &[u8; 32]) -> Result<(), Error> {
+    // Generate an ephemeral key and derive the message key
+    let mut ephemeral = [0; 32];
+    getrandom::getrandom(&mut ephemeral).map_err(Error::other)?;
+    let ephemeral = StaticSecret::from(ephemeral);
+    let (public, server) = (PublicKey::from(&ephemeral), PublicKey::from(*server));
+    let shared = ephemeral.diffie_hellman(&server);
+    let true = shared.was_contributory() else {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid seal key"));
+    };
+    let salt = [public.as_bytes().as_slice(), server.as_bytes().as_slice()].concat();
+    let mut message_key = [0; 32];
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+    hkdf.expand(INFO, &mut message_key).map_err(|_| Error::other("failed to derive message key"))?;
+    let cipher = ChaCha20Poly1305::new(&message_key.into());
+
+    // Write the header
+    let mut source = File::open(source)?;
+    let mut file = File::create(dest)?;
+    file.write_all(MAGIC)?;
+    file.write_all(public.as_bytes())?;
+
+    // Encrypt the chunks; a chunk is the last one if there is no data left after it
+    let (mut chunk, mut index) = (read_chunk(&mut source)?, 0u64);
+    loop {
+        let next = read_chunk(&mut source)?;
+        let last = next.is_empty();
+        let ciphertext = cipher.encrypt(&nonce(index, last), chunk.as_slice());
+        file.write_all(&ciphertext.map_err(|_| Error::other("failed to seal message"))?)?;
+        if last {
+            break;
+        }
+        (chunk, index) = (next, index.saturating_add(1));
+    }
+    file.sync_all()
+}
+
+/// Reads the next plaintext chunk, which is only shorter than [`CHUNK_SIZE`] at the end of the file
+fn read_chunk(source: &mut File) -> Result<Vec<u8>, Error> {
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    source.take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+/// Creates the nonce for the chunk with the given index
+fn nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0; 12];
+    let (counter, flag) = nonce.split_at_mut(11);
+    counter.split_at_mut(3).1.copy_from_slice(&index.to_be_bytes());
+    flag.fill(u8::from(last));
+    Nonce::from(nonce)
+}
@@ -0,0 +1,156 @@
+//! Time helpers to parse schedules
+
+use std::{
+    io::{Error, ErrorKind},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Gets the current UNIX timestamp in seconds
+pub fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+/// Parses an absolute point in time into a UNIX timestamp
+///
+/// # Format
+/// The time can be a UNIX timestamp prefixed with `@` (e.g. `@1792224000`), a time of the day (e.g. `07:00`, refers to the next
+/// occurrence) or a date and time (e.g. `2026-10-17T07:00` or `2026-10-17T07:00:00Z`); all times are UTC.
+pub fn parse_at(at: &str) -> Result<u64, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid time: {at}"));
+
+    // Parse UNIX timestamps; the prefix is required so that e.g. `0700` is not mistaken for a timestamp in 1970
+    if let Some(timestamp) = at.strip_prefix('@') {
+        return timestamp.parse().map_err(|_| invalid());
+    }
+
+    // Parse a time of the day
+    let Some((date, time)) = at.trim_end_matches('Z').split_once('T') else {
+        let second_of_day = parse_time(at).ok_or_else(invalid)?;
+        let now = now();
+        let today = now.saturating_sub(now % 86400).saturating_add(second_of_day);
+        return match today > now {
+            true => Ok(today),
+            false => Ok(today.saturating_add(86400)),
+        };
+    };
+
+    // Parse a date and time
+    let mut fields = date.splitn(3, '-').map(str::parse::<u64>);
+    let (Some(Ok(year @ 1970..=9999)), Some(Ok(month @ 1..=12)), Some(Ok(day @ 1..))) =
+        (fields.next(), fields.next(), fields.next())
+    else {
+        return Err(invalid());
+    };
+    if day > days_in_month(year, month) {
+        return Err(invalid());
+    }
+    let second_of_day = parse_time(time).ok_or_else(invalid)?;
+    Ok(days_from_civil(year, month, day).saturating_mul(86400).saturating_add(second_of_day))
+}
+
+/// Parses a relative delay into seconds
+///
+/// # Format
+/// The delay is a number with an optional unit suffix, i.e. `s` (default), `m`, `h` or `d` (e.g. `30m`)
+pub fn parse_delay(delay: &str) -> Result<u64, Error> {
+    // Split the unit
+    let (value, factor) = match delay.char_indices().last() {
+        Some((index, 's')) => (delay.get(..index), 1),
+        Some((index, 'm')) => (delay.get(..index), 60),
+        Some((index, 'h')) => (delay.get(..index), 3600),
+        Some((index, 'd')) => (delay.get(..index), 86400),
+        _ => (Some(delay), 1),
+    };
+
+    // Parse the value
+    match value.map(str::parse::<u64>) {
+        Some(Ok(value)) => Ok(value.saturating_mul(factor)),
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("invalid delay: {delay}"))),
+    }
+}
+
+/// Parses a `HH:MM` or `HH:MM:SS` time into the second of the day
+fn parse_time(time: &str) -> Option<u64> {
+    let mut fields = time.splitn(3, ':').map(str::parse::<u64>);
+    let (Some(Ok(hours @ 0..=23)), Some(Ok(minutes @ 0..=59)), seconds) = (fields.next(), fields.next(), fields.next())
+    else {
+        return None;
+    };
+    let seconds = match seconds {
+        Some(Ok(seconds @ 0..=59)) => seconds,
+        Some(_) => return None,
+        None => 0,
+    };
+    Some(hours.saturating_mul(3600).saturating_add(minutes.saturating_mul(60)).saturating_add(seconds))
+}
+
+/// Gets the amount of days in the given month
+fn days_in_month(year: u64, month: u64) -> u64 {
+    let is_leap_year = year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+    match month {
+        2 if is_leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Computes the days since the UNIX epoch for a given date
+///
+/// # Important
+/// The year must be at least 1970, the month must be within `1..=12` and the day within `1..=31`
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // Note: This is safe since the caller range-checks all fields, so none of the operations can overflow or underflow
+    #[allow(clippy::arithmetic_side_effects, reason = "see note")]
+    {
+        // See https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+        let year = if month <= 2 { year - 1 } else { year };
+        let (era, year_of_era) = (year / 400, year % 400);
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - 719468
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{now, parse_at};
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse_at("@1792224000").ok(), Some(1792224000));
+        assert!(parse_at("@").is_err());
+        assert!(parse_at("@-1").is_err());
+
+        // Short integers are no timestamps and no valid times of the day
+        assert!(parse_at("0700").is_err());
+        assert!(parse_at("1830").is_err());
+        assert!(parse_at("1792224000").is_err());
+    }
+
+    #[test]
+    fn times_of_day() {
+        let at = parse_at("07:00").unwrap_or_default();
+        assert_eq!(at % 86400, 25200);
+        assert!(at > now() && at <= now() + 86400);
+        assert!(parse_at("24:00").is_err());
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_at("2026-10-17T07:00").ok(), Some(1792220400));
+        assert_eq!(parse_at("2026-10-17T07:00:00Z").ok(), Some(1792220400));
+        assert_eq!(parse_at("2028-02-29T00:00").ok(), Some(1835395200));
+        assert_eq!(parse_at("2000-02-29T00:00").ok(), Some(951782400));
+
+        // Days beyond the end of the month are rejected instead of rolling over
+        assert!(parse_at("2026-02-29T08:00").is_err());
+        assert!(parse_at("2026-02-31T08:00").is_err());
+        assert!(parse_at("2100-02-29T08:00").is_err());
+        assert!(parse_at("2026-04-31T08:00").is_err());
+        assert!(parse_at("2026-10-00T08:00").is_err());
+    }
+}
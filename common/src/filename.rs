@@ -0,0 +1,43 @@
+//! Validation of attachment names
+
+use std::io::{Error, ErrorKind};
+
+/// The maximum length of an attachment name in bytes
+pub const NAME_MAX: usize = 255;
+/// The maximum length of an extension that is kept when a name is truncated
+const EXTENSION_MAX: usize = 16;
+
+/// Validates and sanitizes an attachment name
+///
+/// # Note
+/// Names that are empty, contain control characters or path separators, or start with a dot or a dash (which could be
+/// parsed as a flag by `matrix-commander-rs`) are rejected. Names that are longer than [`NAME_MAX`] bytes are truncated
+/// while keeping their extension.
+pub fn sanitize(name: &str) -> Result<String, Error> {
+    // Reject unsafe names
+    if name.is_empty() || name.contains(char::is_control) || name.contains(['/', '\\']) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("invalid attachment name: {}", name.escape_debug())));
+    }
+    if name.starts_with(['.', '-']) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("attachment name starts with a dot or dash: {name}")));
+    }
+
+    // Keep short names as they are
+    if name.len() <= NAME_MAX {
+        return Ok(name.to_string());
+    }
+
+    // Split the extension if it is reasonably short
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if extension.len() <= EXTENSION_MAX => (stem, format!(".{extension}")),
+        _ => (name, String::new()),
+    };
+
+    // Truncate the stem at a char boundary
+    let mut stem_len = NAME_MAX.saturating_sub(extension.len());
+    while !stem.is_char_boundary(stem_len) {
+        stem_len = stem_len.saturating_sub(1);
+    }
+    let stem = stem.get(..stem_len).unwrap_or_default();
+    Ok(format!("{stem}{extension}"))
+}
@@ -0,0 +1,13 @@
+//! Hex encoding of keys and MACs
+
+/// Decodes a hex string
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // Ensure that the string consists of pairs of hex digits
+    let true = (hex.len().is_multiple_of(2) && hex.bytes().all(|byte| byte.is_ascii_hexdigit())) else {
+        return None;
+    };
+
+    // Decode the pairs
+    let pairs = hex.as_bytes().chunks_exact(2);
+    pairs.map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()).collect()
+}
@@ -0,0 +1,30 @@
+//! Fuzzes the parsing of IPC file names with arbitrary directory entries
+//!
+//! # Note
+//! The server is a binary crate, so the file name module is included directly; run via `cargo fuzz run filename`
+#![no_main]
+
+#[path = "../../src/filename.rs"]
+#[allow(dead_code, reason = "only the parser is fuzzed")]
+mod filename;
+
+use filename::Kind;
+use libfuzzer_sys::fuzz_target;
+use std::{ffi::OsStr, io::ErrorKind, os::unix::ffi::OsStrExt};
+
+fuzz_target!(|entry: &[u8]| {
+    // Classify the entry like the server does when it scans the IPC directory
+    let entry = OsStr::from_bytes(entry);
+    let Some(kind) = Kind::classify(entry) else {
+        return;
+    };
+    assert!(entry.is_ascii(), "classified non-ascii entry");
+
+    // Every raw message name must be parsed to a valid attachment name or be rejected as invalid data
+    if kind == Kind::Raw {
+        match filename::raw_name(entry) {
+            Ok(name) => assert_eq!(filename::sanitize(&name).ok(), Some(name), "accepted invalid attachment name"),
+            Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidData, "unexpected error: {e}"),
+        }
+    }
+});
@@ -0,0 +1,205 @@
+//! Safety checks and initialization of the IPC directory
+
+use crate::{
+    auth::Producers, config::Config, dedup::Dedup, digest::Digest, dispatch::Dispatcher, events::Events,
+    health::Health, ipc::IpcServer, log, seal::Unsealer, sink::Sink, upload::Uploads,
+};
+use std::{
+    fs::{self, File},
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+/// The name of the probe file which is used to check hard link support
+const PROBE: &str = ".sendmatrix-probe";
+/// The name of the hard link to the probe file
+const PROBE_LINK: &str = ".sendmatrix-probe-link";
+
+/// Audits the IPC directory and prints a warning for every unsafe setting
+///
+/// # Note
+/// A symlinked IPC directory or subdirectory, a subdirectory on another filesystem and missing hard link support are
+/// errors, since they allow to redirect messages or break the delivery
+pub fn audit(config: &Config) -> Result<(), Error> {
+    // Ensure that the IPC directory is a real directory
+    let path = Path::new(&config.IPC_PATH);
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_symlink() {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("IPC path is a symlink: {}", path.display())));
+    }
+    if !metadata.is_dir() {
+        return Err(Error::new(ErrorKind::NotADirectory, format!("IPC path is not a directory: {}", path.display())));
+    }
+
+    // Ensure that the subdirectories are real directories on the same filesystem, so that messages can be moved there
+    for subdir in subdirs() {
+        // Get the subdirectory if it exists
+        let subdir = path.join(subdir);
+        let subdir_metadata = match fs::symlink_metadata(&subdir) {
+            Ok(subdir_metadata) => subdir_metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        // Validate the subdirectory
+        if subdir_metadata.is_symlink() || !subdir_metadata.is_dir() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("not a directory: {}", subdir.display())));
+        }
+        if !is_same_filesystem(&metadata, &subdir_metadata) {
+            return Err(Error::new(ErrorKind::CrossesDevices, format!("different filesystem: {}", subdir.display())));
+        }
+    }
+
+    // Ensure that the client can publish messages via hard links and check the permissions
+    let probe = probe(path)?;
+    audit_permissions(path, &metadata, &probe);
+    Ok(())
+}
+
+/// Creates the IPC directory layout with safe permissions
+///
+/// # Note
+/// The IPC directory is assigned to the producer group `IPC_GROUP`, which is created if necessary, and gets the mode
+/// `3770`, so that only the owner and the producers can submit messages, new messages inherit the group, and producers
+/// cannot delete each other's messages. The subdirectories are only accessible by the owner.
+#[cfg(unix)]
+pub fn init(config: &Config) -> Result<(), Error> {
+    use std::{
+        os::unix::fs::{self as unix_fs, PermissionsExt},
+        process::Command,
+    };
+
+    // Get or create the producer group
+    let gid = match group_id(&config.IPC_GROUP)? {
+        Some(gid) => gid,
+        None => {
+            let status = Command::new("groupadd").args(["--system", &config.IPC_GROUP]).status()?;
+            let (true, Some(gid)) = (status.success(), group_id(&config.IPC_GROUP)?) else {
+                return Err(Error::other(format!("failed to create group: {}", config.IPC_GROUP)));
+            };
+            log::info!("Created group: {}", config.IPC_GROUP);
+            gid
+        }
+    };
+
+    // Create the IPC directory
+    let path = Path::new(&config.IPC_PATH);
+    fs::create_dir_all(path)?;
+    unix_fs::chown(path, None, Some(gid))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o3770))?;
+
+    // Create the subdirectories
+    for subdir in subdirs() {
+        let subdir = path.join(subdir);
+        fs::create_dir_all(&subdir)?;
+        fs::set_permissions(&subdir, fs::Permissions::from_mode(0o700))?;
+    }
+
+    // Print status
+    log::info!("Initialized IPC directory: {}", path.display());
+    Ok(())
+}
+/// Creates the IPC directory layout with safe permissions
+///
+/// # Note
+/// Permissions and groups are only supported on unix
+#[cfg(not(unix))]
+pub fn init(_config: &Config) -> Result<(), Error> {
+    Err(Error::new(ErrorKind::Unsupported, "IPC initialization is only supported on unix"))
+}
+
+/// The subdirectories of the IPC directory that are managed by the server
+fn subdirs() -> [&'static str; 11] {
+    [
+        Dedup::SUBDIR,
+        Digest::SUBDIR,
+        Events::SUBDIR,
+        Dispatcher::EXPIRED_SUBDIR,
+        Health::SUBDIR,
+        Producers::SUBDIR,
+        Dispatcher::QUARANTINE_SUBDIR,
+        IpcServer::SPOOL_SUBDIR,
+        Sink::SUBDIR,
+        Unsealer::SUBDIR,
+        Uploads::SUBDIR,
+    ]
+}
+
+/// Creates a probe file and a hard link to it and returns the probe file metadata
+pub fn probe(path: &Path) -> Result<fs::Metadata, Error> {
+    // Remove stale probe files
+    let (probe, link) = (path.join(PROBE), path.join(PROBE_LINK));
+    let remove = |file: &Path| match fs::remove_file(file) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    };
+    remove(&link)?;
+
+    // Create the probe file and link it
+    let result = File::create(&probe).and_then(|_| fs::hard_link(&probe, &link)).and_then(|_| fs::metadata(&probe));
+    remove(&probe)?;
+    remove(&link)?;
+    result.map_err(|e| Error::new(e.kind(), format!("cannot create and link files in IPC path: {e}")))
+}
+
+/// Checks if two directories are on the same filesystem
+#[cfg(unix)]
+fn is_same_filesystem(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev()
+}
+/// Checks if two directories are on the same filesystem
+///
+/// # Note
+/// Device IDs are only supported on unix, so directories are assumed to be on the same filesystem on other platforms
+#[cfg(not(unix))]
+fn is_same_filesystem(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    true
+}
+
+/// Prints a warning if the IPC directory has unsafe permissions
+#[cfg(unix)]
+fn audit_permissions(path: &Path, metadata: &fs::Metadata, probe: &fs::Metadata) {
+    use std::os::unix::fs::MetadataExt;
+
+    // Check the mode bits
+    let mode = metadata.mode();
+    if mode & 0o002 != 0 && mode & 0o1000 == 0 {
+        log::warning!("IPC path is world-writable without sticky bit, anyone can replace messages: {}", path.display());
+    }
+
+    // Check the ownership; the probe file is owned by the server
+    if metadata.uid() != probe.uid() && metadata.uid() != 0 {
+        log::warning!(
+            "IPC path is owned by UID {} instead of the server UID {}: {}",
+            metadata.uid(),
+            probe.uid(),
+            path.display()
+        );
+    }
+}
+/// Prints a warning if the IPC directory has unsafe permissions
+///
+/// # Note
+/// Mode bits and owners are only supported on unix
+#[cfg(not(unix))]
+fn audit_permissions(_path: &Path, _metadata: &fs::Metadata, _probe: &fs::Metadata) {
+    // Nothing to check
+}
+
+/// Looks up the ID of a group by its name
+#[cfg(unix)]
+fn group_id(name: &str) -> Result<Option<u32>, Error> {
+    let groups = fs::read_to_string("/etc/group")?;
+    for line in groups.lines() {
+        // Parse the `name:password:gid:members`-line
+        let mut fields = line.split(':');
+        let (Some(group), Some(_), Some(gid)) = (fields.next(), fields.next(), fields.next()) else {
+            continue;
+        };
+        if group == name {
+            return gid.parse().map(Some).map_err(|e| Error::new(ErrorKind::InvalidData, e));
+        }
+    }
+    Ok(None)
+}
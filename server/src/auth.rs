@@ -0,0 +1,314 @@
+//! Authentication of producers via signed IPC messages
+
+use crate::{
+    config::Config,
+    envelope::Metadata,
+    html, log,
+    message::{Attachment, Message},
+    private, time,
+};
+use hmac::{Hmac, Mac};
+use sendmatrix_common::hex::decode_hex;
+use sha2::Sha256;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug, Formatter},
+    fs,
+    io::{self, Error, ErrorKind},
+    path::PathBuf,
+};
+
+/// The signature of an IPC message
+#[derive(Debug, Clone)]
+pub struct Signature {
+    /// The claimed producer name
+    pub producer: Option<String>,
+    /// The UNIX timestamp when the message was signed
+    pub signed_at: Option<u64>,
+    /// The unique nonce of the message
+    pub nonce: Option<String>,
+    /// The hex-encoded HMAC-SHA256 over the signed regions
+    pub mac: String,
+    /// The signed regions of the IPC file, i.e. the entire file except for the signature line
+    pub signed: Vec<Attachment>,
+}
+
+/// The nonce of a fresh signed message which is remembered once the message has been processed
+#[derive(Debug, Clone)]
+pub struct Nonce {
+    /// The producer name and the nonce, separated by a space
+    key: String,
+    /// The UNIX timestamp after which the message is stale anyway, so that the nonce can be forgotten
+    expiry: u64,
+}
+
+/// A producer key which is redacted in debug output
+#[derive(Clone)]
+struct Key(Vec<u8>);
+impl Debug for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Key(<redacted>)")
+    }
+}
+
+/// Verifies the producers of IPC messages
+#[derive(Debug)]
+pub struct Producers {
+    /// The producer keys by producer name
+    keys: BTreeMap<String, Key>,
+    /// The allowed owner UIDs and their producer names
+    owners: BTreeMap<u32, String>,
+    /// Whether unsigned messages are rejected
+    required: bool,
+    /// Whether messages are prefixed with the name of their verified producer
+    prefix: bool,
+    /// The maximum age of signed messages in seconds
+    max_age: u64,
+    /// The path to the state file with the nonces of the processed signed messages
+    path: PathBuf,
+    /// The nonces of the processed signed messages together with their expiry
+    nonces: BTreeMap<String, u64>,
+}
+impl Producers {
+    /// The subdirectory of the IPC directory that contains the nonce state file
+    ///
+    /// # Note
+    /// The subdirectory is only accessible by the server, so that producers cannot forget their nonces to replay messages
+    pub const SUBDIR: &'static str = "producers";
+    /// The state file name within the subdirectory
+    const STATE_FILE: &'static str = "nonces.state";
+    /// The tolerated clock skew between producers and the server in seconds
+    const CLOCK_SKEW: u64 = 300;
+
+    /// Creates a new producer verifier
+    ///
+    /// # Format
+    /// The keys are configured as `;`-separated list of hex-encoded `name=key` entries, e.g.
+    /// `backup=8f3a...;monitoring=c01d...`
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        let mut keys = BTreeMap::new();
+        for entry in config.PRODUCER_KEYS.0.split(';').filter(|entry| !entry.trim().is_empty()) {
+            // Parse the entry
+            // Note: The key is not printed in error messages since it is secret
+            let Some((name, key)) = entry.split_once('=') else {
+                return Err(Error::new(ErrorKind::InvalidData, "invalid producer key entry"));
+            };
+            let name = name.trim();
+            let Some(key) = decode_hex(key.trim()).filter(|key| !key.is_empty()) else {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid key for producer: {name}")));
+            };
+
+            // Register the key
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid producer name: {name}")));
+            }
+            keys.insert(name.to_string(), Key(key));
+        }
+
+        // Ensure that signed messages can be verified at all
+        if config.REQUIRE_SIGNATURE && keys.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "signatures are required, but no producer keys are set"));
+        }
+
+        // Parse the owner allowlist
+        let mut owners = BTreeMap::new();
+        for entry in config.PRODUCER_UIDS.split(';').filter(|entry| !entry.trim().is_empty()) {
+            // Parse the entry
+            let Some((uid, name)) = entry.split_once('=') else {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid producer UID entry: {entry}")));
+            };
+            let Ok(uid) = uid.trim().parse() else {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid producer UID: {uid}")));
+            };
+
+            // Register the owner
+            let name = name.trim();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid producer name: {name}")));
+            }
+            owners.insert(uid, name.to_string());
+        }
+        if !owners.is_empty() && !cfg!(unix) {
+            return Err(Error::new(ErrorKind::Unsupported, "producer UIDs are only supported on unix"));
+        }
+
+        // Ensure that the nonces of signed messages are only remembered for a limited time
+        if !keys.is_empty() && config.SIGNATURE_MAX_AGE == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "the maximum signature age must not be 0"));
+        }
+
+        // Init self and load the nonces of the processed signed messages
+        let path = private::dir(config, Self::SUBDIR)?.join(Self::STATE_FILE);
+        let (required, prefix, max_age) = (config.REQUIRE_SIGNATURE, config.PRODUCER_PREFIX, config.SIGNATURE_MAX_AGE);
+        let mut this = Self { keys, owners, required, prefix, max_age, path, nonces: BTreeMap::new() };
+        let state = match fs::read_to_string(&this.path) {
+            Ok(state) => state,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(this),
+            Err(e) => return Err(e),
+        };
+        for line in state.lines() {
+            // Parse the line
+            let Some((expiry, key)) = line.split_once(' ') else {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid nonce state: {line}")));
+            };
+            let expiry = expiry.parse().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            this.nonces.insert(key.to_string(), expiry);
+        }
+
+        // Print status and return instance
+        log::info!("Remembered nonces: {}", this.nonces.len());
+        Ok(this)
+    }
+
+    /// Verifies the signature and the owner of a message and returns the name of the verified producer if any
+    ///
+    /// # Note
+    /// Rejected messages yield an error of kind [`ErrorKind::PermissionDenied`]. If the message is signed, the name of
+    /// the signing producer takes precedence over the name of the owner.
+    pub fn verify(&self, metadata: &Metadata) -> Result<Option<String>, Error> {
+        let signer = self.verify_signature(metadata)?;
+        let owner = self.verify_owner(metadata)?;
+        Ok(signer.or(owner))
+    }
+
+    /// Verifies the signature of a message and returns the name of the signing producer if any
+    ///
+    /// # Note
+    /// If no producer keys are configured, signatures are ignored. Messages with an invalid signature and, if required,
+    /// unsigned messages are rejected.
+    fn verify_signature(&self, metadata: &Metadata) -> Result<Option<String>, Error> {
+        // Check if the message is signed
+        let (Some(signature), false) = (&metadata.signature, self.keys.is_empty()) else {
+            return match self.required {
+                true => Err(Error::new(ErrorKind::PermissionDenied, "unsigned message")),
+                false => Ok(None),
+            };
+        };
+
+        // Get the producer key
+        let Some(producer) = &signature.producer else {
+            return Err(Error::new(ErrorKind::PermissionDenied, "signed message without producer"));
+        };
+        let Some(Key(key)) = self.keys.get(producer) else {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("unknown producer: {producer}")));
+        };
+
+        // Compute and compare the MAC
+        let mut hmac = Hmac::<Sha256>::new_from_slice(key).map_err(Error::other)?;
+        for region in &signature.signed {
+            io::copy(&mut region.open()?, &mut hmac)?;
+        }
+        let verified = decode_hex(&signature.mac).is_some_and(|mac| hmac.verify_slice(&mac).is_ok());
+        let true = verified else {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("invalid signature from producer: {producer}"),
+            ));
+        };
+        Ok(Some(producer.clone()))
+    }
+
+    /// Checks that a signed message is fresh and has not been processed before and returns its nonce if any
+    ///
+    /// # Note
+    /// Stale and replayed messages yield an error of kind [`ErrorKind::PermissionDenied`]. The age of a scheduled
+    /// message is computed from its not-before time. The returned nonce must be remembered via [`Self::consume`] once
+    /// the message has been processed, so that a message that could not be sent is retried after a restart.
+    pub fn check_replay(&self, metadata: &Metadata, now: u64) -> Result<Option<Nonce>, Error> {
+        // Check if the message is signed; unsigned messages have been checked by `verify` already
+        let (Some(signature), false) = (&metadata.signature, self.keys.is_empty()) else {
+            return Ok(None);
+        };
+        let (Some(producer), Some(signed_at), Some(nonce)) =
+            (&signature.producer, signature.signed_at, &signature.nonce)
+        else {
+            return Err(Error::new(ErrorKind::PermissionDenied, "signed message without timestamp or nonce"));
+        };
+
+        // Check the signature age
+        let producer = producer.as_str();
+        if signed_at > now.saturating_add(Self::CLOCK_SKEW) {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("future signature from producer: {producer}")));
+        }
+        let expiry = signed_at.max(metadata.not_before.unwrap_or_default()).saturating_add(self.max_age);
+        if now >= expiry {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("stale signature from producer: {producer}")));
+        }
+
+        // Check the nonce
+        if nonce.is_empty() || nonce.contains(char::is_whitespace) {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("invalid nonce from producer: {producer}")));
+        }
+        let key = format!("{producer} {nonce}");
+        if self.nonces.contains_key(&key) {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("replayed message from producer: {producer}")));
+        }
+        Ok(Some(Nonce { key, expiry }))
+    }
+
+    /// Remembers the nonce of a processed signed message and forgets the nonces of stale messages
+    pub fn consume(&mut self, nonce: Option<Nonce>) -> Result<(), Error> {
+        // Check if there is a nonce
+        let Some(Nonce { key, expiry }) = nonce else {
+            return Ok(());
+        };
+
+        // Remember the nonce and forget all nonces of messages that would be rejected as stale anyway
+        let now = time::now();
+        self.nonces.insert(key, expiry);
+        self.nonces.retain(|_, expiry| now < *expiry);
+
+        // Atomically replace the state file
+        let mut state = String::new();
+        for (key, expiry) in &self.nonces {
+            state.push_str(&format!("{expiry} {key}\n"));
+        }
+        private::replace(&self.path, state.as_bytes())
+    }
+
+    /// Verifies that the owner of a message is allowed and returns the producer name of the owner if any
+    ///
+    /// # Note
+    /// If no owner UIDs are configured, all owners are allowed
+    fn verify_owner(&self, metadata: &Metadata) -> Result<Option<String>, Error> {
+        // Check if there is an allowlist
+        if self.owners.is_empty() {
+            return Ok(None);
+        }
+
+        // Check the owner
+        let Some(owner) = metadata.owner else {
+            return Err(Error::new(ErrorKind::PermissionDenied, "message without owner"));
+        };
+        match self.owners.get(&owner) {
+            Some(name) => Ok(Some(name.clone())),
+            None => Err(Error::new(ErrorKind::PermissionDenied, format!("unauthorized owner UID: {owner}"))),
+        }
+    }
+
+    /// Prepends the name of the verified producer to a message if configured
+    pub fn format(&self, metadata: &Metadata, message: &mut Message) {
+        // Get the prefix
+        let (true, Some(producer)) = (self.prefix, &metadata.producer) else {
+            // Nothing to format
+            return;
+        };
+
+        // Prepend the prefix to the text and, for HTML messages, to the plaintext fallback
+        let prefix = format!("[{producer}] ");
+        match message {
+            Message::Plaintext { text } | Message::Notice { text } | Message::Emote { text } => {
+                text.splice(0..0, prefix.bytes());
+            }
+            Message::Markdown { markdown } => {
+                markdown.splice(0..0, prefix.bytes());
+            }
+            Message::Html { html: body, fallback } => {
+                body.splice(0..0, html::escape(&prefix).bytes());
+                fallback.splice(0..0, prefix.bytes());
+            }
+            Message::Raw { .. } => (/* attachments are not formatted */),
+            Message::Reaction { .. } | Message::Redaction { .. } => (/* reactions and redactions are not formatted */),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! A matrix backend that wraps `matrix-commander-rs`
+
+use crate::{
+    config::Config, envelope::Envelope, events::Relation, log, matrix::Backend, media, message::Message,
+    metrics::Metrics,
+};
+use std::{
+    io::{self, Error, ErrorKind, Read},
+    process::{Command, Stdio},
+};
+
+/// The `matrix-commander-rs` backend
+#[derive(Debug)]
+pub struct Commander<'a> {
+    /// The config
+    config: &'a Config,
+    /// The metrics
+    metrics: &'a Metrics,
+}
+impl<'a> Commander<'a> {
+    /// Creates a new `matrix-commander-rs` backend
+    pub fn new(config: &'a Config, metrics: &'a Metrics) -> Result<Self, Error> {
+        // Init self and get username to ensure matrix commander exists and is configured
+        let this = Self { config, metrics };
+        let username = this.matrix_commander(&["--whoami"], &mut io::empty())?;
+
+        // Print status and return instance
+        log::info!("User: `{}`", username.trim_end());
+        Ok(this)
+    }
+
+    /// Executes a matrix commander command
+    fn matrix_commander(&self, args: &[&str], data: &mut dyn Read) -> Result<String, Error> {
+        // Start the matrix commander
+        let mut matrix_commander = Command::new(&self.config.MATRIX_PATH)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        // Write all data to stdin and close it
+        // Note: Since we create a dedicated pipe for stdin, this should never fail
+        #[allow(clippy::expect_used, reason = "see note")]
+        let mut stdin = matrix_commander.stdin.take().expect("failed to get stdin from spawned child process");
+        io::copy(data, &mut stdin)?;
+        drop(stdin);
+
+        // Wait for matrix commander to complete
+        let result = matrix_commander.wait_with_output()?;
+        self.metrics.commander_exit(result.status.code());
+        let true = result.status.success() else {
+            // Signalize that matrix commander failed
+            return Err(Error::from(ErrorKind::Other));
+        };
+
+        // Return stdout
+        match String::from_utf8(result.stdout) {
+            Ok(stdout) => Ok(stdout),
+            Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+        }
+    }
+}
+impl Backend for Commander<'_> {
+    /// Rejects messages with relations, reactions and redactions since `matrix-commander-rs` does not report the event
+    /// IDs to relate to
+    fn check(&self, envelope: &Envelope) -> Result<(), Error> {
+        let metadata = &envelope.metadata;
+        let is_related = metadata.reply_to.is_some() || metadata.thread.is_some() || metadata.edit.is_some();
+        match &envelope.message {
+            Message::Reaction { .. } | Message::Redaction { .. } => Err(Error::new(
+                ErrorKind::Unsupported,
+                "reactions and redactions are not supported by matrix-commander-rs",
+            )),
+            _ if is_related || metadata.thread_files => Err(Error::new(
+                ErrorKind::Unsupported,
+                "replies, threads and edits are not supported by matrix-commander-rs",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends a message to the matrix-commander's configured default room
+    ///
+    /// # Note
+    /// `matrix-commander-rs` does not report the event ID of sent messages, so no events can be tracked and messages are
+    /// never sent with a relation. HTML messages are sent via `--html`, where `matrix-commander-rs` derives the plaintext
+    /// body from the HTML itself, so the fallback is not used.
+    fn send(&self, envelope: &Envelope, _relation: Option<&Relation>) -> Result<Option<String>, Error> {
+        // Prepare message
+        let (mut args, mut data): (_, Box<dyn Read>) = match &envelope.message {
+            Message::Plaintext { text } => (vec!["--message", "-"], Box::new(text.as_slice())),
+            Message::Markdown { markdown } => (vec!["--message", "-", "--markdown"], Box::new(markdown.as_slice())),
+            Message::Notice { text } => (vec!["--message", "-", "--notice"], Box::new(text.as_slice())),
+            Message::Emote { text } => (vec!["--message", "-", "--emote"], Box::new(text.as_slice())),
+            Message::Html { html, .. } => (vec!["--message", "-", "--html"], Box::new(html.as_slice())),
+            Message::Raw { name, contents } => {
+                // Pass the detected content type, so that images, audio and video can be displayed inline
+                let mimetype = media::sniff(name, &contents.head(media::SNIFF_SIZE)?);
+                (vec!["--file", "-", "--file-name", name, "--mime", mimetype], Box::new(contents.open()?))
+            }
+            Message::Reaction { .. } | Message::Redaction { .. } => {
+                // Reactions and redactions are no messages of their own
+                return Err(Error::new(ErrorKind::InvalidInput, "reactions and redactions cannot be sent as message"));
+            }
+        };
+
+        // Send low priority text messages as notice
+        let is_text =
+            matches!(envelope.message, Message::Plaintext { .. } | Message::Markdown { .. } | Message::Html { .. });
+        if is_text && envelope.metadata.priority.is_notice(self.config) {
+            args.push("--notice");
+        }
+
+        // Send message
+        self.matrix_commander(&args, &mut data)?;
+        Ok(None)
+    }
+
+    /// Fails since `matrix-commander-rs` does not support reactions
+    fn react(&self, _event_id: &str, _reaction: &str) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "reactions are not supported by matrix-commander-rs"))
+    }
+
+    /// Fails since `matrix-commander-rs` does not support redactions
+    fn redact(&self, _event_id: &str, _reason: &str) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "redactions are not supported by matrix-commander-rs"))
+    }
+}
@@ -1,26 +1,142 @@
 //! The server configuration
 
 use std::{
+    convert::Infallible,
     env::{self, VarError},
+    fmt::{self, Debug, Formatter},
     io::{Error, ErrorKind},
     str::FromStr,
 };
 
+/// A secret config value which is redacted in debug output
+#[derive(Clone, Default)]
+pub struct Secret(pub String);
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.0.is_empty() {
+            true => write!(f, "Secret(<empty>)"),
+            false => write!(f, "Secret(<redacted>)"),
+        }
+    }
+}
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_string()))
+    }
+}
+
 /// The server configuration
 #[derive(Debug, Clone)]
-#[allow(non_snake_case)]
+#[allow(non_snake_case, reason = "config keys mirror the environment variables")]
 pub struct Config {
     /// The path to the IPC directory
     pub IPC_PATH: String,
+    /// The producer group that is assigned to the IPC directory by `--init-ipc`
+    pub IPC_GROUP: String,
+    /// The matrix backend, i.e. `matrix-commander` or `sink`
+    pub BACKEND: String,
     /// The path to the matrix commander binary
     pub MATRIX_PATH: String,
+    /// The outbox directory for the `sink` backend; empty writes to stdout
+    pub SINK_PATH: String,
+    /// The window in seconds within which repeated identical messages are suppressed; `0` disables deduplication
+    pub DEDUP_WINDOW: u64,
+    /// Whether text messages without an explicit `digest` header are held back for the digest
+    pub DIGEST_DEFAULT: bool,
+    /// The digest schedule, either `hourly` or `daily@HH:MM` (UTC)
+    pub DIGEST_SCHEDULE: String,
+    /// The maximum age in seconds after which a pending message is considered stale; `0` disables the limit
+    pub MAX_AGE: u64,
+    /// Whether a single summary notice is sent for all messages that expired
+    pub EXPIRY_NOTICE: bool,
+    /// The prefix for low priority messages
+    pub PRIORITY_PREFIX_LOW: String,
+    /// The prefix for normal priority messages
+    pub PRIORITY_PREFIX_NORMAL: String,
+    /// The prefix for high priority messages
+    pub PRIORITY_PREFIX_HIGH: String,
+    /// The prefix for critical messages
+    pub PRIORITY_PREFIX_CRITICAL: String,
+    /// Whether low priority messages are sent as `m.notice`
+    pub PRIORITY_NOTICE_LOW: bool,
+    /// Whether critical messages ping the entire room via `@room`
+    pub PRIORITY_PING_CRITICAL: bool,
+    /// The maximum attachment size in bytes
+    pub UPLOAD_SIZE_MAX: u64,
+    /// The compression for attachments that exceed the upload limit, i.e. `none`, `gzip` or `zstd`
+    pub UPLOAD_COMPRESSION: String,
+    /// Whether attachments that still exceed the upload limit are split into parts
+    pub UPLOAD_SPLIT: bool,
+    /// The mention aliases as `;`-separated list of `name=user-id,user-id,...` entries
+    pub MENTION_ALIASES: String,
+    /// The producer keys as `;`-separated list of `name=hex-key` entries to verify signed messages
+    pub PRODUCER_KEYS: Secret,
+    /// Whether unsigned messages are quarantined instead of being sent
+    pub REQUIRE_SIGNATURE: bool,
+    /// Whether messages are prefixed with the name of their verified producer
+    pub PRODUCER_PREFIX: bool,
+    /// The maximum age in seconds of signed messages, after which they are rejected as stale
+    pub SIGNATURE_MAX_AGE: u64,
+    /// The allowed owner UIDs of IPC files as `;`-separated list of `uid=name` entries; empty allows all owners
+    pub PRODUCER_UIDS: String,
+    /// The maximum amount of messages per owner UID and hour; `0` disables the limit
+    pub QUOTA_MESSAGES_HOURLY: u64,
+    /// The maximum amount of message bytes per owner UID and hour; `0` disables the limit
+    pub QUOTA_BYTES_HOURLY: u64,
+    /// The hex-encoded X25519 private key to decrypt sealed messages; empty rejects sealed messages
+    pub SEAL_PRIVATE_KEY: Secret,
+    /// The maximum age in seconds of the last poll loop before `healthcheck` reports the server as stale
+    pub HEALTH_MAX_AGE: u64,
+    /// The path to the Prometheus metrics file for the node exporter's textfile collector; empty disables metrics
+    pub METRICS_PATH: String,
+    /// The minimum log level, i.e. `debug`, `info`, `warn` or `error`
+    pub LOG_LEVEL: String,
+    /// The log format, i.e. `text` or `json`
+    pub LOG_FORMAT: String,
 }
 impl Config {
     /// Loads the config from environment
     pub fn from_env() -> Result<Self, Error> {
         Ok(Self {
             IPC_PATH: Self::get_or("IPC_PATH", "/var/run/sendmatrix")?,
+            IPC_GROUP: Self::get_or("IPC_GROUP", "sendmatrix")?,
+            BACKEND: Self::get_or("BACKEND", "matrix-commander")?,
             MATRIX_PATH: Self::get_or("MATRIX_PATH", "/usr/bin/matrix-commander-rs")?,
+            SINK_PATH: Self::get_or("SINK_PATH", "")?,
+            DEDUP_WINDOW: Self::get_or("DEDUP_WINDOW", 0u64)?,
+            DIGEST_DEFAULT: Self::get_or("DIGEST_DEFAULT", false)?,
+            DIGEST_SCHEDULE: Self::get_or("DIGEST_SCHEDULE", "hourly")?,
+            MAX_AGE: Self::get_or("MAX_AGE", 0u64)?,
+            EXPIRY_NOTICE: Self::get_or("EXPIRY_NOTICE", true)?,
+            PRIORITY_PREFIX_LOW: Self::get_or("PRIORITY_PREFIX_LOW", "")?,
+            PRIORITY_PREFIX_NORMAL: Self::get_or("PRIORITY_PREFIX_NORMAL", "")?,
+            PRIORITY_PREFIX_HIGH: Self::get_or("PRIORITY_PREFIX_HIGH", "⚠️")?,
+            PRIORITY_PREFIX_CRITICAL: Self::get_or("PRIORITY_PREFIX_CRITICAL", "🚨")?,
+            PRIORITY_NOTICE_LOW: Self::get_or("PRIORITY_NOTICE_LOW", true)?,
+            PRIORITY_PING_CRITICAL: Self::get_or("PRIORITY_PING_CRITICAL", true)?,
+            UPLOAD_SIZE_MAX: Self::get_or("UPLOAD_SIZE_MAX", 2u64 * 1024 * 1024)?,
+            UPLOAD_COMPRESSION: Self::get_or("UPLOAD_COMPRESSION", "none")?,
+            UPLOAD_SPLIT: Self::get_or("UPLOAD_SPLIT", false)?,
+            MENTION_ALIASES: Self::get_or("MENTION_ALIASES", "")?,
+            PRODUCER_KEYS: Self::get_or("PRODUCER_KEYS", "")?,
+            REQUIRE_SIGNATURE: Self::get_or("REQUIRE_SIGNATURE", false)?,
+            PRODUCER_PREFIX: Self::get_or("PRODUCER_PREFIX", false)?,
+            SIGNATURE_MAX_AGE: Self::get_or("SIGNATURE_MAX_AGE", 7u64 * 24 * 60 * 60)?,
+            PRODUCER_UIDS: Self::get_or("PRODUCER_UIDS", "")?,
+            QUOTA_MESSAGES_HOURLY: Self::get_or("QUOTA_MESSAGES_HOURLY", 0u64)?,
+            QUOTA_BYTES_HOURLY: Self::get_or("QUOTA_BYTES_HOURLY", 0u64)?,
+            SEAL_PRIVATE_KEY: Self::get_or("SEAL_PRIVATE_KEY", "")?,
+            HEALTH_MAX_AGE: Self::get_or("HEALTH_MAX_AGE", 300u64)?,
+            METRICS_PATH: Self::get_or("METRICS_PATH", "")?,
+            LOG_LEVEL: Self::get_or("LOG_LEVEL", "info")?,
+            LOG_FORMAT: Self::get_or("LOG_FORMAT", "text")?,
         })
     }
 
@@ -0,0 +1,253 @@
+//! Duplicate suppression for repeated identical messages
+
+use crate::{
+    config::Config,
+    envelope::Envelope,
+    log,
+    message::{Attachment, Message},
+    private, time,
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Error, ErrorKind, Write},
+    path::PathBuf,
+};
+
+/// A tracked message fingerprint
+#[derive(Debug, Clone)]
+struct Entry {
+    /// The UNIX timestamp when the suppression window started
+    since: u64,
+    /// The amount of suppressed repetitions
+    repeated: u64,
+    /// A short human-readable preview of the message
+    preview: String,
+}
+
+/// Suppresses repeated identical messages within a configurable window
+#[derive(Debug)]
+pub struct Dedup {
+    /// The path to the state file
+    path: PathBuf,
+    /// The suppression window in seconds; `0` disables the deduplication
+    window: u64,
+    /// The tracked fingerprints
+    entries: BTreeMap<u64, Entry>,
+}
+impl Dedup {
+    /// The subdirectory of the IPC directory that contains the state file
+    ///
+    /// # Note
+    /// The subdirectory is only accessible by the server, so that producers cannot forge the suppression state
+    pub const SUBDIR: &'static str = "dedup";
+    /// The state file name within the subdirectory
+    const STATE_FILE: &'static str = "dedup.state";
+    /// The maximum length of a message preview
+    const PREVIEW_MAX: usize = 64;
+
+    /// Creates a new deduplicator and loads the persistent state if any
+    pub fn load(config: &Config) -> Result<Self, Error> {
+        // Init self
+        let path = private::dir(config, Self::SUBDIR)?.join(Self::STATE_FILE);
+        let mut this = Self { path, window: config.DEDUP_WINDOW, entries: BTreeMap::new() };
+        if this.window == 0 {
+            // Deduplication is disabled
+            return Ok(this);
+        }
+
+        // Load the state file
+        let state = match fs::read_to_string(&this.path) {
+            Ok(state) => state,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(this),
+            Err(e) => return Err(e),
+        };
+        for line in state.lines() {
+            // Parse the line
+            let mut fields = line.splitn(4, ' ');
+            let (Some(fingerprint), Some(since), Some(repeated), Some(preview)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid dedup state: {line}")));
+            };
+
+            // Parse the values and register the entry
+            let fingerprint =
+                u64::from_str_radix(fingerprint, 16).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let since = since.parse().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let repeated = repeated.parse().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            this.entries.insert(fingerprint, Entry { since, repeated, preview: preview.to_string() });
+        }
+
+        // Print status and return instance
+        log::info!("Dedup entries: {}", this.entries.len());
+        Ok(this)
+    }
+
+    /// Checks if the message is a repetition within the current window and counts it if so
+    pub fn is_duplicate(&mut self, envelope: &Envelope) -> Result<bool, Error> {
+        // Ignore messages if deduplication is disabled
+        if self.window == 0 {
+            return Ok(false);
+        }
+
+        // Check if we have a matching entry within the window
+        let fingerprint = Self::fingerprint(envelope)?;
+        let Some(entry) = self.entries.get_mut(&fingerprint) else {
+            return Ok(false);
+        };
+        let true = time::now() < entry.since.saturating_add(self.window) else {
+            return Ok(false);
+        };
+
+        // Count the repetition
+        entry.repeated = entry.repeated.saturating_add(1);
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Registers a successfully sent message and starts a new suppression window for it
+    pub fn register(&mut self, envelope: &Envelope) -> Result<(), Error> {
+        // Ignore messages if deduplication is disabled
+        if self.window == 0 {
+            return Ok(());
+        }
+
+        // Register the entry
+        let entry = Entry { since: time::now(), repeated: 0, preview: Self::preview(&envelope.message) };
+        self.entries.insert(Self::fingerprint(envelope)?, entry);
+        self.save()
+    }
+
+    /// Removes all entries with an elapsed window and returns a "repeated N times" follow-up for each suppressed message
+    pub fn expire(&mut self) -> Result<Vec<Envelope>, Error> {
+        // Collect all expired entries
+        let now = time::now();
+        let expired: Vec<u64> = (self.entries.iter())
+            .filter(|(_, entry)| now >= entry.since.saturating_add(self.window))
+            .map(|(fingerprint, _)| *fingerprint)
+            .collect();
+        if expired.is_empty() {
+            // Nothing to do
+            return Ok(Vec::new());
+        }
+
+        // Remove the entries and create the follow-ups
+        let mut followups = Vec::new();
+        for fingerprint in expired {
+            let Some(entry) = self.entries.remove(&fingerprint) else {
+                continue;
+            };
+            if entry.repeated > 0 {
+                let text = format!("Previous message repeated {} times: {}", entry.repeated, entry.preview);
+                followups.push(Envelope::new(Message::Plaintext { text: text.into_bytes() }));
+            }
+        }
+
+        // Persist the state
+        self.save()?;
+        Ok(followups)
+    }
+
+    /// Persists the current state
+    fn save(&self) -> Result<(), Error> {
+        // Serialize the entries
+        let mut state = String::new();
+        for (fingerprint, Entry { since, repeated, preview }) in &self.entries {
+            state.push_str(&format!("{fingerprint:016x} {since} {repeated} {preview}\n"));
+        }
+
+        // Atomically replace the state file
+        private::replace(&self.path, state.as_bytes())
+    }
+
+    /// Computes the fingerprint of a message using the 64 bit FNV-1a hash
+    ///
+    /// # Note
+    /// If the message has a dedup key, only the key is used for the fingerprint so that messages with varying contents
+    /// (e.g. timestamps) can be deduplicated too. Attachments are hashed by their name and contents, so the fingerprint
+    /// fails if an attachment cannot be read.
+    fn fingerprint(envelope: &Envelope) -> Result<u64, Error> {
+        // Collect the fingerprint fields
+        let mut fnv1a = Fnv1a::default();
+        let fields: [&[u8]; 2] = match (&envelope.metadata.dedup_key, &envelope.message) {
+            (Some(key), _) => [b"key", key.as_bytes()],
+            (None, Message::Raw { name, contents }) => {
+                fnv1a.attachment(name, contents)?;
+                return Ok(fnv1a.0);
+            }
+            (None, message @ (Message::Reaction { target, .. } | Message::Redaction { target, .. })) => {
+                [target.as_bytes(), message.payload()]
+            }
+            (None, message) => [message.kind().as_bytes(), message.payload()],
+        };
+
+        // Hash the fields and the attachments of a group
+        for field in fields {
+            fnv1a.update(field);
+            fnv1a.update(b"\0");
+        }
+        if envelope.metadata.dedup_key.is_none() {
+            for (name, contents) in &envelope.attachments {
+                fnv1a.attachment(name, contents)?;
+            }
+        }
+        Ok(fnv1a.0)
+    }
+
+    /// Creates a short single-line preview of a message
+    fn preview(message: &Message) -> String {
+        // Get the message text
+        let text = match message {
+            Message::Raw { name, .. } => String::from_utf8_lossy(name.as_bytes()),
+            Message::Reaction { target, .. } | Message::Redaction { target, .. } => {
+                String::from_utf8_lossy(target.as_bytes())
+            }
+            message => String::from_utf8_lossy(message.text().unwrap_or_default()),
+        };
+
+        // Take the first line and truncate it
+        let line = text.lines().next().unwrap_or_default();
+        match line.char_indices().nth(Self::PREVIEW_MAX) {
+            Some((index, _)) => format!("{}…", line.get(..index).unwrap_or_default()),
+            None => line.to_string(),
+        }
+    }
+}
+
+/// A 64 bit FNV-1a hasher
+#[derive(Debug, Clone, Copy)]
+struct Fnv1a(u64);
+impl Fnv1a {
+    /// Hashes the given bytes
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    /// Hashes the name and streams the contents of an attachment
+    fn attachment(&mut self, name: &str, contents: &Attachment) -> Result<(), Error> {
+        self.update(name.as_bytes());
+        self.update(b"\0");
+        io::copy(&mut contents.open()?, self)?;
+        self.update(b"\0");
+        Ok(())
+    }
+}
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+impl Write for Fnv1a {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
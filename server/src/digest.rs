@@ -0,0 +1,269 @@
+//! Digest mode that aggregates low-priority messages into periodic summaries
+
+use crate::{
+    auth::Producers,
+    config::Config,
+    dispatch::Dispatcher,
+    envelope::{Envelope, Metadata},
+    ipc::IpcServer,
+    log,
+    matrix::Matrix,
+    message::Message,
+    metrics::Metrics,
+    time,
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// The digest schedule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// Flush the digest at the start of every hour
+    Hourly,
+    /// Flush the digest every day at the given second of the day (UTC)
+    Daily {
+        /// The second of the day
+        at: u64,
+    },
+}
+impl Schedule {
+    /// Computes the next flush time after the given UNIX timestamp
+    pub fn next_after(&self, now: u64) -> u64 {
+        match self {
+            Self::Hourly => now.saturating_sub(now % 3600).saturating_add(3600),
+            Self::Daily { at } => {
+                // Get the flush time of the current day and move to the next day if it has already passed
+                let today = now.saturating_sub(now % 86400).saturating_add(*at);
+                match today > now {
+                    true => today,
+                    false => today.saturating_add(86400),
+                }
+            }
+        }
+    }
+}
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Parse the schedule
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("invalid digest schedule: {s}"));
+        if s == "hourly" {
+            return Ok(Self::Hourly);
+        }
+
+        // Parse the daily schedule
+        let (hours, minutes) = s.strip_prefix("daily@").and_then(|time| time.split_once(':')).ok_or_else(invalid)?;
+        let (Ok(hours @ 0..=23), Ok(minutes @ 0..=59)) = (hours.parse::<u64>(), minutes.parse::<u64>()) else {
+            return Err(invalid());
+        };
+        Ok(Self::Daily { at: hours.saturating_mul(3600).saturating_add(minutes.saturating_mul(60)) })
+    }
+}
+
+/// Holds back low-priority messages and flushes them as one combined markdown message on a schedule
+#[derive(Debug)]
+pub struct Digest<'a> {
+    /// The config
+    config: &'a Config,
+    /// The digest schedule
+    schedule: Schedule,
+    /// The UNIX timestamp of the next flush
+    next_flush: u64,
+}
+impl<'a> Digest<'a> {
+    /// The subdirectory of the IPC directory where held messages are stored
+    pub const SUBDIR: &'static str = "digest";
+    /// The maximum size of a single digest message; larger digests are split into multiple messages
+    const MESSAGE_SIZE_MAX: usize = 32 * 1024;
+
+    /// Creates a new digest
+    pub fn new(config: &'a Config) -> Result<Self, Error> {
+        let schedule: Schedule = config.DIGEST_SCHEDULE.parse()?;
+        Ok(Self { config, schedule, next_flush: schedule.next_after(time::now()) })
+    }
+
+    /// Checks if a message should be held back for the digest
+    ///
+    /// # Note
+    /// Attachments, reactions, redactions, messages that are tracked or relate to other events and messages that
+    /// mention someone are never held back
+    pub fn accepts(&self, envelope: &Envelope) -> bool {
+        let Metadata { event_key, reply_to, thread, edit, mentions, ping_room, .. } = &envelope.metadata;
+        match envelope.message {
+            Message::Raw { .. } | Message::Reaction { .. } | Message::Redaction { .. } => false,
+            _ if !envelope.attachments.is_empty() => false,
+            _ if [event_key, reply_to, thread, edit].iter().any(|key| key.is_some()) => false,
+            _ if !mentions.is_empty() || *ping_room => false,
+            _ => envelope.metadata.digest.unwrap_or(self.config.DIGEST_DEFAULT),
+        }
+    }
+
+    /// Sends the digest if it is due
+    ///
+    /// # Note
+    /// Held messages get the same producer and priority prefixes as directly sent messages. Held messages that cannot
+    /// be read or verified anymore are quarantined, and messages that have been removed in the meantime are skipped.
+    pub fn flush(
+        &mut self,
+        matrix: &Matrix,
+        server: &IpcServer,
+        producers: &Producers,
+        metrics: &Metrics,
+    ) -> Result<(), Error> {
+        // Check if the digest is due
+        let now = time::now();
+        if now < self.next_flush {
+            return Ok(());
+        }
+        self.next_flush = self.schedule.next_after(now);
+
+        // Collect the held messages, grouped by tag and ordered by arrival
+        let (mut held, mut groups) = (Vec::new(), BTreeMap::<String, Vec<String>>::new());
+        for path in self.held()? {
+            // Read the message and verify its producer; held messages have no attachments, so sealed messages are not
+            // needed decrypted anymore
+            let _correlation = log::correlate(path.file_name().map(|name| name.to_string_lossy().into_owned()));
+            let verified = server.read_envelope(&path).and_then(|mut envelope| {
+                envelope.metadata.producer = producers.verify(&envelope.metadata)?;
+                Ok(envelope)
+            });
+            server.release(&path)?;
+            let Envelope { mut message, metadata, .. } = match verified {
+                Ok(envelope) => envelope,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => {
+                    log::warning!("Quarantined held message: {e}");
+                    metrics.archived(Dispatcher::QUARANTINE_SUBDIR);
+                    self.quarantine(&path)?;
+                    continue;
+                }
+            };
+
+            // Apply the producer and priority prefixes like for directly sent messages
+            producers.format(&metadata, &mut message);
+            metadata.priority.format(&mut message, self.config);
+            let text = match message {
+                Message::Raw { name, .. } => format!("Attachment `{name}`"),
+                message => String::from_utf8_lossy(message.text().unwrap_or_default()).into_owned(),
+            };
+
+            // Format the message as list item
+            let item = format!("- {}\n", text.trim_end().replace('\n', "\n  "));
+            let tag = metadata.tag.unwrap_or_else(|| String::from("Other"));
+            groups.entry(tag).or_default().push(item);
+            held.push(path);
+        }
+
+        // Assemble and send the digest
+        if !held.is_empty() {
+            for markdown in Self::assemble(held.len(), groups) {
+                matrix.send(&Envelope::new(Message::Markdown { markdown: markdown.into_bytes() }))?;
+            }
+        }
+
+        // Remove the sent messages
+        for path in held {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => (/* file has been removed */),
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves a held message that cannot be read into the quarantine directory
+    fn quarantine(&self, path: &Path) -> Result<(), Error> {
+        let Some(filename) = path.file_name() else {
+            // Indicate that the path is invalid
+            return Err(Error::from(ErrorKind::InvalidInput));
+        };
+        let dir = Path::new(&self.config.IPC_PATH).join(Dispatcher::QUARANTINE_SUBDIR);
+        fs::create_dir_all(&dir)?;
+        fs::rename(path, dir.join(filename))
+    }
+
+    /// Lists all held messages ordered by their modification time
+    fn held(&self) -> Result<Vec<PathBuf>, Error> {
+        // Read the digest directory
+        let dir = Path::new(&self.config.IPC_PATH).join(Self::SUBDIR);
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        // Collect all messages
+        let mut held = Vec::new();
+        'read_dir: for maybe_entry in entries {
+            // Get the entry and ensure it's a message file
+            let entry = maybe_entry?;
+            let path = entry.path();
+            let true = (entry.file_type()?.is_file() && IpcServer::is_message(&path)) else {
+                continue 'read_dir;
+            };
+
+            // Store the path together with the modification time
+            let modified = entry.metadata()?.modified()?;
+            held.push((modified, path));
+        }
+
+        // Sort the messages
+        held.sort();
+        Ok(held.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Assembles the markdown digest messages from the grouped message items
+    fn assemble(count: usize, groups: BTreeMap<String, Vec<String>>) -> Vec<String> {
+        let mut messages = Vec::new();
+        let mut markdown = format!("**Digest** ({count} messages)\n");
+        for (tag, items) in groups {
+            // Start a new group; the tag is supplied by the producer, so it must not inject any markup
+            let heading = format!("\n### {}\n", Self::escape(&tag));
+            markdown.push_str(&heading);
+
+            // Append the items and start a new message if the current one becomes too large
+            for item in items {
+                if markdown.len().saturating_add(item.len()) > Self::MESSAGE_SIZE_MAX {
+                    messages.push(markdown);
+                    markdown = format!("**Digest** (continued)\n{heading}");
+                }
+                markdown.push_str(&item);
+            }
+        }
+
+        // Finalize the last message
+        messages.push(markdown);
+        messages
+    }
+
+    /// Escapes all markdown syntax, i.e. all ASCII punctuation, so that text is rendered literally
+    fn escape(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for char_ in text.chars().filter(|char_| !char_.is_control()) {
+            if char_.is_ascii_punctuation() {
+                escaped.push('\\');
+            }
+            escaped.push(char_);
+        }
+        escaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Digest;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn escape_tags() {
+        let groups = BTreeMap::from([(String::from("<img src=x> **[a](b)**"), vec![String::from("- item\n")])]);
+        let digest = Digest::assemble(1, groups);
+        assert_eq!(digest, ["**Digest** (1 messages)\n\n### \\<img src\\=x\\> \\*\\*\\[a\\]\\(b\\)\\*\\*\n- item\n"]);
+    }
+}
@@ -0,0 +1,305 @@
+//! The message dispatcher that routes IPC messages through the processing stages
+
+use crate::{
+    auth::Producers,
+    config::Config,
+    dedup::Dedup,
+    digest::Digest,
+    envelope::Envelope,
+    events::{Events, Relation},
+    health::Health,
+    ipc::IpcServer,
+    log,
+    matrix::Matrix,
+    mentions::Mentions,
+    message::Message,
+    metrics::Metrics,
+    quota::Quotas,
+    time,
+    upload::Uploads,
+};
+use std::io::{Error, ErrorKind};
+
+/// The message dispatcher
+#[derive(Debug)]
+pub struct Dispatcher<'a> {
+    /// The config
+    config: &'a Config,
+    /// The IPC server
+    server: IpcServer<'a>,
+    /// The matrix adapter
+    matrix: Matrix<'a>,
+    /// The duplicate suppression
+    dedup: Dedup,
+    /// The digest
+    digest: Digest<'a>,
+    /// The tracked events
+    events: Events,
+    /// The mention resolver
+    mentions: Mentions,
+    /// The attachment preparer
+    uploads: Uploads,
+    /// The producer verifier
+    producers: Producers,
+    /// The per-owner quotas
+    quotas: Quotas,
+    /// The health reporter
+    health: Health,
+    /// The metrics
+    metrics: &'a Metrics,
+    /// The amount of expired messages since the last expiry notice
+    expired: u64,
+}
+impl<'a> Dispatcher<'a> {
+    /// The subdirectory of the IPC directory where expired messages are moved to
+    pub const EXPIRED_SUBDIR: &'static str = "expired";
+    /// The subdirectory of the IPC directory where rejected messages are moved to
+    pub const QUARANTINE_SUBDIR: &'static str = "quarantine";
+
+    /// Creates a new dispatcher
+    pub fn new(config: &'a Config, metrics: &'a Metrics) -> Result<Self, Error> {
+        let matrix = Matrix::new(config, metrics)?;
+        let server = IpcServer::new(config)?;
+        let dedup = Dedup::load(config)?;
+        let digest = Digest::new(config)?;
+        let events = Events::load(config)?;
+        let mentions = Mentions::new(config)?;
+        let uploads = Uploads::new(config)?;
+        let producers = Producers::new(config)?;
+        let quotas = Quotas::new(config);
+        let health = Health::new(config)?;
+        Ok(Self {
+            config,
+            server,
+            matrix,
+            dedup,
+            digest,
+            events,
+            mentions,
+            uploads,
+            producers,
+            quotas,
+            health,
+            metrics,
+            expired: 0,
+        })
+    }
+
+    /// Performs the periodic tasks, processes the next pending message if any and reports the health and the metrics
+    ///
+    /// # Important
+    /// If there is no pending message available, this function blocks for one poll interval
+    pub fn tick(&mut self) -> Result<(), Error> {
+        let result = self.process();
+        let failed = self.server.current().filter(|_| result.is_err());
+        let reported = self.health.report(&result, failed, self.matrix.last_send(), self.server.queue_len());
+        let exported = self.metrics.report(self.server.queue_len());
+        result.and(reported).and(exported)
+    }
+
+    /// Performs the periodic tasks and processes the next pending message if any
+    fn process(&mut self) -> Result<(), Error> {
+        // Send the digest if it is due
+        self.digest.flush(&self.matrix, &self.server, &self.producers, self.metrics)?;
+
+        // Send the follow-ups for suppressed repetitions
+        for followup in self.dedup.expire()? {
+            self.matrix.send(&followup)?;
+        }
+
+        // Get the next message and quarantine messages that cannot be decrypted or decoded
+        let maybe_envelope = match self.server.next_message() {
+            Err(e) if matches!(e.kind(), ErrorKind::PermissionDenied | ErrorKind::InvalidData) => {
+                log::warning!("Quarantined message: {e}");
+                self.metrics.archived(Self::QUARANTINE_SUBDIR);
+                return self.server.archive_message(Self::QUARANTINE_SUBDIR);
+            }
+            maybe_envelope => maybe_envelope?,
+        };
+        let Some(mut envelope) = maybe_envelope else {
+            // Send the expiry notice once the queue has been drained, i.e. not if a message has just been cancelled
+            if self.server.has_message()? {
+                return Ok(());
+            }
+            return self.notify_expired();
+        };
+        self.metrics.received(envelope.message.kind());
+        if self.server.current().is_some_and(|message| self.health.is_retry(message)) {
+            self.metrics.retried(envelope.message.kind());
+        }
+
+        // Quarantine messages from unauthenticated producers, replayed messages or producers that exceeded their quota
+        let now = time::now();
+        let verified = self.producers.verify(&envelope.metadata);
+        let verified =
+            verified.and_then(|producer| Ok((producer, self.producers.check_replay(&envelope.metadata, now)?)));
+        let verified = verified.and_then(|verified| self.quotas.check(&envelope, now).map(|_| verified));
+        let nonce = match verified {
+            Ok((producer, nonce)) => {
+                envelope.metadata.producer = producer;
+                nonce
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::PermissionDenied | ErrorKind::QuotaExceeded) => {
+                log::warning!("Quarantined message: {e}");
+                self.metrics.archived(Self::QUARANTINE_SUBDIR);
+                return self.server.archive_message(Self::QUARANTINE_SUBDIR);
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Deliver the message and remember its nonce once it has been processed, so that it cannot be replayed
+        self.deliver(envelope)?;
+        self.producers.consume(nonce)
+    }
+
+    /// Delivers a verified message, i.e. sends it or holds it back, suppresses it or drops it as requested
+    fn deliver(&mut self, envelope: Envelope) -> Result<(), Error> {
+        // Quarantine messages that the backend cannot deliver as requested
+        match self.matrix.check(&envelope) {
+            Err(e) if e.kind() == ErrorKind::Unsupported => {
+                log::warning!("Quarantined message: {e}");
+                self.metrics.archived(Self::QUARANTINE_SUBDIR);
+                return self.server.archive_message(Self::QUARANTINE_SUBDIR);
+            }
+            result => result?,
+        }
+
+        // Drop stale messages
+        if envelope.is_expired(self.config.MAX_AGE, time::now()) {
+            self.expired = self.expired.saturating_add(1);
+            self.metrics.archived(Self::EXPIRED_SUBDIR);
+            log::info!("Expired message");
+            return self.server.archive_message(Self::EXPIRED_SUBDIR);
+        }
+
+        // Suppress repetitions
+        if self.dedup.is_duplicate(&envelope)? {
+            log::info!("Suppressed repeated message");
+            return self.server.complete_message();
+        }
+
+        // Handle reactions and redactions
+        if let Some(target) = envelope.message.target() {
+            self.update(&envelope, target)?;
+            self.dedup.register(&envelope)?;
+            return self.server.complete_message();
+        }
+
+        // Hold the message back for the digest
+        if self.digest.accepts(&envelope) {
+            log::info!("Held message back for the digest");
+            self.server.archive_message(Digest::SUBDIR)?;
+            return self.dedup.register(&envelope);
+        }
+
+        // Format and send message
+        let mut formatted = envelope.clone();
+        self.producers.format(&envelope.metadata, &mut formatted.message);
+        formatted.metadata.priority.format(&mut formatted.message, self.config);
+        formatted.metadata.mentions = self.mentions.resolve(&envelope.metadata.mentions);
+        formatted.metadata.ping_room |= envelope.metadata.priority.is_ping(self.config);
+        Mentions::format(&formatted.metadata, &mut formatted.message);
+        let relation = self.events.relation(&envelope.metadata);
+        let event_id = self.send_attachment(&formatted, relation.as_ref())?;
+        if let Some(event_id) = &event_id {
+            self.events.record(&envelope.metadata, event_id)?;
+        }
+        self.send_group(&envelope, event_id)?;
+        self.dedup.register(&envelope)?;
+        self.server.complete_message()
+    }
+
+    /// Sends the attachments of a group in order after their caption
+    ///
+    /// # Note
+    /// If the caption is part of a thread, the attachments are sent into the same thread; otherwise they are threaded
+    /// under the caption if requested
+    fn send_group(&self, envelope: &Envelope, caption: Option<String>) -> Result<(), Error> {
+        // Resolve the relation of the attachments
+        let relation = match (self.events.relation(&envelope.metadata), caption) {
+            (Some(relation @ Relation::Thread { .. }), _) => Some(relation),
+            (_, Some(root)) if envelope.metadata.thread_files => Some(Relation::Thread { root }),
+            _ => None,
+        };
+
+        // Send the attachments
+        for (name, contents) in &envelope.attachments {
+            let attachment = Envelope::new(Message::Raw { name: name.clone(), contents: contents.clone() });
+            self.send_attachment(&attachment, relation.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Sends a message; attachments that exceed the upload limit are compressed or split into parts if configured
+    ///
+    /// # Note
+    /// Returns the event ID of the message or the first part. If an attachment cannot be brought within the upload
+    /// limit, a notice is sent instead.
+    fn send_attachment(&self, envelope: &Envelope, relation: Option<&Relation>) -> Result<Option<String>, Error> {
+        // Get the attachment
+        let Message::Raw { name, contents } = &envelope.message else {
+            return self.matrix.send_related(envelope, relation);
+        };
+
+        // Prepare the attachment
+        let limit = self.config.UPLOAD_SIZE_MAX;
+        let parts = match self.uploads.prepare(name, contents, limit) {
+            Ok(parts) => parts,
+            Err(e) if e.kind() == ErrorKind::FileTooLarge => {
+                self.uploads.cleanup()?;
+                let text = format!("Attachment `{name}` is too large to be sent ({} of {limit} bytes)", contents.len);
+                return self.matrix.send(&Envelope::new(Message::Notice { text: text.into_bytes() }));
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Send the parts
+        let mut event_id = None;
+        for (name, contents) in parts {
+            let message = Message::Raw { name, contents };
+            let part = Envelope { message, metadata: envelope.metadata.clone(), attachments: Vec::new() };
+            let part_event_id = self.matrix.send_related(&part, relation)?;
+            event_id = event_id.or(part_event_id);
+        }
+        self.uploads.cleanup()?;
+        Ok(event_id)
+    }
+
+    /// Reacts to or redacts the previously sent event with the given key
+    ///
+    /// # Note
+    /// Reactions and redactions of unknown events are dropped
+    fn update(&mut self, envelope: &Envelope, target: &str) -> Result<(), Error> {
+        // Resolve the target event
+        let Some(event_id) = self.events.get(target).map(str::to_string) else {
+            log::warning!("Unknown event key: {target}");
+            return Ok(());
+        };
+
+        // Apply the reaction or redaction
+        let text = String::from_utf8_lossy(envelope.message.payload());
+        match envelope.message {
+            Message::Reaction { .. } => self.matrix.react(&event_id, &text),
+            Message::Redaction { .. } => {
+                self.matrix.redact(&event_id, &text)?;
+                self.events.forget(&event_id)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends a single summary notice for all messages that expired since the last notice
+    fn notify_expired(&mut self) -> Result<(), Error> {
+        // Check if there are any expired messages
+        if self.expired == 0 || !self.config.EXPIRY_NOTICE {
+            self.expired = 0;
+            return Ok(());
+        }
+
+        // Send the notice
+        let text = format!("{} messages expired before they could be sent", self.expired);
+        self.matrix.send(&Envelope::new(Message::Plaintext { text: text.into_bytes() }))?;
+        self.expired = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+//! An IPC message envelope
+
+use crate::{
+    auth::Signature,
+    filename, html,
+    message::{Attachment, Message},
+    priority::Priority,
+};
+use std::{
+    io::{Error, ErrorKind},
+    ops::Range,
+    str::FromStr,
+};
+
+/// The message metadata
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// A client-supplied key to deduplicate messages with varying contents
+    pub dedup_key: Option<String>,
+    /// Whether the message should be held back for the next digest instead of being sent immediately
+    pub digest: Option<bool>,
+    /// An optional source tag which is used to group messages in a digest
+    pub tag: Option<String>,
+    /// The UNIX timestamp before which the message must not be sent
+    pub not_before: Option<u64>,
+    /// The UNIX timestamp after which the message is stale and must not be sent anymore
+    pub expires: Option<u64>,
+    /// The message priority
+    pub priority: Priority,
+    /// A client-chosen key to record the event ID of the sent message under
+    pub event_key: Option<String>,
+    /// The key of a previously sent event to reply to
+    pub reply_to: Option<String>,
+    /// The key of the thread root to send the message into
+    pub thread: Option<String>,
+    /// The key of a previously sent event to replace with this message
+    pub edit: Option<String>,
+    /// The mentioned user IDs or aliases
+    pub mentions: Vec<String>,
+    /// Whether the message pings the entire room via `@room`
+    pub ping_room: bool,
+    /// Whether the attachments of a group are threaded under the caption
+    pub thread_files: bool,
+    /// The UNIX timestamp when the message was submitted
+    ///
+    /// # Note
+    /// This is not a header field, but the modification time of the IPC file
+    pub received: u64,
+    /// The UID of the owner of the IPC file
+    ///
+    /// # Note
+    /// This is not a header field, but the owner of the IPC file; it is only available on unix
+    pub owner: Option<u32>,
+    /// The signature of the IPC file
+    ///
+    /// # Note
+    /// This is not a header field, but is assembled from the `producer`, `signed-at`, `nonce` and `signature` fields
+    /// and the IPC file
+    pub signature: Option<Signature>,
+    /// The name of the verified producer
+    ///
+    /// # Note
+    /// This is not a header field, but is set once the signature or the owner has been verified
+    pub producer: Option<String>,
+}
+
+/// A message together with its metadata
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    /// The message
+    pub message: Message,
+    /// The message metadata
+    pub metadata: Metadata,
+    /// The attachments that are sent in order after the message, which then serves as caption
+    pub attachments: Vec<(String, Attachment)>,
+}
+impl Envelope {
+    /// The maximum header size
+    pub const HEADER_SIZE_MAX: usize = 4096;
+
+    /// Creates a new envelope without metadata
+    pub fn new(message: Message) -> Self {
+        Self { message, metadata: Metadata::default(), attachments: Vec::new() }
+    }
+
+    /// The size of the message contents in bytes, including all attachments
+    pub fn size(&self) -> u64 {
+        let size = match &self.message {
+            Message::Raw { contents, .. } => contents.len,
+            message => message.payload().len() as u64,
+        };
+        self.attachments.iter().fold(size, |size, (_, contents)| size.saturating_add(contents.len))
+    }
+
+    /// Checks if the message has expired, either by its own expiry time or because it is older than `max_age` seconds
+    ///
+    /// # Note
+    /// The age of a scheduled message is computed from its not-before time; a `max_age` of `0` disables the check
+    pub fn is_expired(&self, max_age: u64, now: u64) -> bool {
+        // Check the message expiry
+        if self.metadata.expires.is_some_and(|expires| now >= expires) {
+            return true;
+        }
+
+        // Check the message age
+        let since = self.metadata.not_before.unwrap_or_default().max(self.metadata.received);
+        max_age > 0 && now >= since.saturating_add(max_age)
+    }
+
+    /// Decodes an envelope from its decoded header and the payload
+    ///
+    /// # Format
+    /// An envelope consists of `key=value` header lines, followed by an empty line and the raw payload. The header
+    /// must contain a `type` and, for raw attachments, a `name`. HTML messages can have an optional single-line
+    /// `fallback`; if it is missing, the fallback is derived from the HTML. Reactions and redactions must contain the
+    /// event key of the message they `target`. Text messages can be followed by a group of attachments, which are
+    /// declared in order as `attachment=<length> <name>` header lines and appended to the payload in the same order.
+    pub fn decode(header: Header, payload: Payload) -> Result<Self, Error> {
+        // Split the payload
+        let Header { type_, name, fallback, target, metadata, .. } = header;
+        let (payload, attachments) = match payload {
+            Payload::Text { text, attachments } => (text, attachments),
+            Payload::Attachment(contents) => match (type_.as_deref(), name, target) {
+                (Some("raw"), Some(name), None) => {
+                    let message = Message::Raw { name: filename::sanitize(&name)?, contents };
+                    return Ok(Self { message, metadata, attachments: Vec::new() });
+                }
+                _ => return Err(Error::new(ErrorKind::InvalidData, "invalid message type")),
+            },
+        };
+
+        // Validate the attachments
+        let attachments = attachments.into_iter().map(|(name, contents)| Ok((filename::sanitize(&name)?, contents)));
+        let attachments = attachments.collect::<Result<Vec<_>, Error>>()?;
+        if !attachments.is_empty() && target.is_some() {
+            return Err(Error::new(ErrorKind::InvalidData, "reactions and redactions cannot have attachments"));
+        }
+
+        // Assemble the message
+        let message = match (type_.as_deref(), name, target) {
+            (Some("reaction"), None, Some(target)) if !payload.is_empty() => {
+                Message::Reaction { target, reaction: payload }
+            }
+            (Some("redaction"), None, Some(target)) => Message::Redaction { target, reason: payload },
+            (_, _, Some(_)) => return Err(Error::new(ErrorKind::InvalidData, "invalid message type")),
+            (Some("plaintext" | "text"), None, None) => Message::Plaintext { text: payload },
+            (Some("markdown"), None, None) => Message::Markdown { markdown: payload },
+            (Some("notice"), None, None) => Message::Notice { text: payload },
+            (Some("emote"), None, None) => Message::Emote { text: payload },
+            (Some("html"), None, None) => {
+                let fallback = fallback.unwrap_or_else(|| html::to_plaintext(&String::from_utf8_lossy(&payload)));
+                Message::Html { html: payload, fallback: fallback.into_bytes() }
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "invalid message type")),
+        };
+        Ok(Self { message, metadata, attachments })
+    }
+}
+
+/// The payload of an envelope
+#[derive(Debug, Clone)]
+pub enum Payload {
+    /// The in-memory payload of a text message
+    Text {
+        /// The message text
+        text: Vec<u8>,
+        /// The attachments that follow the text
+        attachments: Vec<(String, Attachment)>,
+    },
+    /// The payload of an attachment, which is streamed from the IPC file
+    Attachment(Attachment),
+}
+
+/// A decoded envelope header
+#[derive(Debug, Clone, Default)]
+pub struct Header {
+    /// The message type
+    pub type_: Option<String>,
+    /// The attachment name
+    pub name: Option<String>,
+    /// The plaintext fallback for HTML messages
+    pub fallback: Option<String>,
+    /// The event key of the message targeted by a reaction or redaction
+    pub target: Option<String>,
+    /// The lengths and names of the attachments that follow the payload
+    pub attachments: Vec<(u64, String)>,
+    /// The claimed producer of a signed message
+    pub producer: Option<String>,
+    /// The UNIX timestamp when a signed message was signed
+    pub signed_at: Option<u64>,
+    /// The unique nonce of a signed message
+    pub nonce: Option<String>,
+    /// The hex-encoded signature and the byte range of the signature line within the envelope
+    pub signature: Option<(String, Range<u64>)>,
+    /// The message metadata
+    pub metadata: Metadata,
+}
+impl Header {
+    /// Whether the envelope contains an attachment, whose payload is not loaded into memory
+    pub fn is_attachment(&self) -> bool {
+        self.type_.as_deref() == Some("raw")
+    }
+
+    /// Decodes the header from the beginning of an envelope and returns it together with the payload offset
+    ///
+    /// # Note
+    /// It is sufficient to pass the first [`Envelope::HEADER_SIZE_MAX`] bytes of an envelope
+    pub fn decode(bytes: &[u8]) -> Result<(Self, usize), Error> {
+        // Find the end of the header
+        let prefix = bytes.get(..Envelope::HEADER_SIZE_MAX.saturating_add(2)).unwrap_or(bytes);
+        let Some(header_len) = prefix.windows(2).position(|window| window == b"\n\n") else {
+            return Err(Error::new(ErrorKind::InvalidData, "missing envelope header"));
+        };
+        let header = bytes.get(..header_len).unwrap_or_default();
+        let header = std::str::from_utf8(header).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        // Parse the header
+        let (mut this, mut line_start) = (Self::default(), 0u64);
+        for line in header.split_inclusive('\n') {
+            // Get the line and its byte range within the envelope
+            // Note: The last header line is terminated by the first line break of the header separator
+            let line_range = line_start..line_start.saturating_add(line.len() as u64);
+            line_start = line_range.end;
+            let line = line.strip_suffix('\n').unwrap_or(line);
+            let line_end = line_range.start.saturating_add(line.len() as u64).saturating_add(1);
+            let line = line.strip_suffix('\r').unwrap_or(line);
+
+            // Split the line into key-value
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid header line: {line}")));
+            };
+
+            // Store the value
+            let metadata = &mut this.metadata;
+            match key {
+                "type" => this.type_ = Some(value.to_string()),
+                "name" => this.name = Some(value.to_string()),
+                "fallback" => this.fallback = Some(value.to_string()),
+                "target" => this.target = Some(value.to_string()),
+                "producer" => this.producer = Some(value.to_string()),
+                "signed-at" => this.signed_at = Some(Self::parse(key, value)?),
+                "nonce" => this.nonce = Some(value.to_string()),
+                "signature" => this.signature = Some((value.to_string(), line_range.start..line_end)),
+                "attachment" => {
+                    let Some((len, name)) = value.split_once(' ') else {
+                        return Err(Error::new(ErrorKind::InvalidData, format!("invalid attachment: {value}")));
+                    };
+                    this.attachments.push((Self::parse(key, len)?, name.to_string()));
+                }
+                "dedup-key" => metadata.dedup_key = Some(value.to_string()),
+                "digest" => metadata.digest = Some(Self::parse(key, value)?),
+                "tag" => metadata.tag = Some(value.to_string()),
+                "not-before" => metadata.not_before = Some(Self::parse(key, value)?),
+                "expires" => metadata.expires = Some(Self::parse(key, value)?),
+                "priority" => metadata.priority = Self::parse(key, value)?,
+                "event-key" => metadata.event_key = Some(value.to_string()),
+                "reply-to" => metadata.reply_to = Some(value.to_string()),
+                "thread" => metadata.thread = Some(value.to_string()),
+                "edit" => metadata.edit = Some(value.to_string()),
+                "mentions" => metadata.mentions = value.split(',').map(str::to_string).collect(),
+                "ping-room" => metadata.ping_room = Self::parse(key, value)?,
+                "thread-files" => metadata.thread_files = Self::parse(key, value)?,
+                _ => return Err(Error::new(ErrorKind::InvalidData, format!("unknown header key: {key}"))),
+            }
+        }
+
+        // Ensure that the message has at most one relation
+        let Metadata { reply_to, thread, edit, .. } = &this.metadata;
+        if [reply_to, thread, edit].into_iter().flatten().count() > 1 {
+            return Err(Error::new(ErrorKind::InvalidData, "conflicting header keys: reply-to, thread, edit"));
+        }
+        if this.is_attachment() && !this.attachments.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "raw messages cannot have attachments"));
+        }
+        Ok((this, header_len.saturating_add(2)))
+    }
+
+    /// Parses a header value
+    fn parse<T>(key: &str, value: &str) -> Result<T, Error>
+    where
+        T: FromStr,
+    {
+        match value.parse() {
+            Ok(value) => Ok(value),
+            Err(_) => Err(Error::new(ErrorKind::InvalidData, format!("invalid value for header key {key}: {value}"))),
+        }
+    }
+}
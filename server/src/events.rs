@@ -0,0 +1,158 @@
+//! Tracking of sent events by client-chosen keys to reply to, thread under or edit them later
+
+use crate::{config::Config, envelope::Metadata, log, private, time};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Error, ErrorKind},
+    path::PathBuf,
+};
+
+/// A relation of a message to a previously sent event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Relation {
+    /// A reply to the given event
+    Reply {
+        /// The event ID of the replied-to event
+        event_id: String,
+    },
+    /// A message within the thread of the given root event
+    Thread {
+        /// The event ID of the thread root
+        root: String,
+    },
+    /// A replacement of the given event's contents
+    Edit {
+        /// The event ID of the original event
+        event_id: String,
+    },
+}
+
+/// A tracked event
+#[derive(Debug, Clone)]
+struct Entry {
+    /// The UNIX timestamp when the event was recorded
+    since: u64,
+    /// The event ID
+    event_id: String,
+}
+
+/// Maps client-chosen keys to the IDs of sent events
+#[derive(Debug)]
+pub struct Events {
+    /// The path to the state file
+    path: PathBuf,
+    /// The tracked events
+    entries: BTreeMap<String, Entry>,
+}
+impl Events {
+    /// The subdirectory of the IPC directory that contains the state file
+    ///
+    /// # Note
+    /// The subdirectory is only accessible by the server, so that producers cannot redirect relations to other events
+    pub const SUBDIR: &'static str = "events";
+    /// The state file name within the subdirectory
+    const STATE_FILE: &'static str = "events.state";
+    /// The maximum amount of tracked events; if exceeded, the oldest events are forgotten
+    const ENTRIES_MAX: usize = 4096;
+
+    /// Creates a new event tracker and loads the persistent state if any
+    pub fn load(config: &Config) -> Result<Self, Error> {
+        // Init self
+        let path = private::dir(config, Self::SUBDIR)?.join(Self::STATE_FILE);
+        let mut this = Self { path, entries: BTreeMap::new() };
+
+        // Load the state file
+        let state = match fs::read_to_string(&this.path) {
+            Ok(state) => state,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(this),
+            Err(e) => return Err(e),
+        };
+        for line in state.lines() {
+            // Parse the line
+            let mut fields = line.splitn(3, ' ');
+            let (Some(since), Some(event_id), Some(key)) = (fields.next(), fields.next(), fields.next()) else {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid events state: {line}")));
+            };
+
+            // Parse the values and register the entry
+            let since = since.parse().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            this.entries.insert(key.to_string(), Entry { since, event_id: event_id.to_string() });
+        }
+
+        // Print status and return instance
+        log::info!("Tracked events: {}", this.entries.len());
+        Ok(this)
+    }
+
+    /// Gets the event ID for the given key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let entry = self.entries.get(key)?;
+        Some(&entry.event_id)
+    }
+
+    /// Forgets all keys of a redacted event
+    pub fn forget(&mut self, event_id: &str) -> Result<(), Error> {
+        self.entries.retain(|_, entry| entry.event_id != event_id);
+        self.save()
+    }
+
+    /// Resolves the relation of a message to a previously sent event
+    ///
+    /// # Note
+    /// If the referenced key is unknown, the message is sent without relation; in case of threads and edits, it then
+    /// becomes the thread root or the original event respectively
+    pub fn relation(&self, metadata: &Metadata) -> Option<Relation> {
+        if let Some(event_id) = metadata.reply_to.as_deref().and_then(|key| self.get(key)) {
+            return Some(Relation::Reply { event_id: event_id.to_string() });
+        }
+        if let Some(root) = metadata.thread.as_deref().and_then(|key| self.get(key)) {
+            return Some(Relation::Thread { root: root.to_string() });
+        }
+        if let Some(event_id) = metadata.edit.as_deref().and_then(|key| self.get(key)) {
+            return Some(Relation::Edit { event_id: event_id.to_string() });
+        }
+        None
+    }
+
+    /// Records the event ID of a sent message under its event key, or under its thread or edit key if it has become the
+    /// thread root or original event
+    pub fn record(&mut self, metadata: &Metadata, event_id: &str) -> Result<(), Error> {
+        // Collect the keys to record the event under
+        let thread = metadata.thread.as_ref().filter(|key| !self.entries.contains_key(*key));
+        let edit = metadata.edit.as_ref().filter(|key| !self.entries.contains_key(*key));
+        let keys: Vec<String> = [metadata.event_key.as_ref(), thread, edit].into_iter().flatten().cloned().collect();
+        if keys.is_empty() {
+            // Nothing to record
+            return Ok(());
+        }
+
+        // Record the event
+        let since = time::now();
+        for key in keys {
+            self.entries.insert(key, Entry { since, event_id: event_id.to_string() });
+        }
+
+        // Forget the oldest events if necessary
+        while self.entries.len() > Self::ENTRIES_MAX {
+            let oldest = self.entries.iter().min_by_key(|(_, entry)| entry.since).map(|(key, _)| key.clone());
+            let Some(oldest) = oldest else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        self.save()
+    }
+
+    /// Persists the current state
+    fn save(&self) -> Result<(), Error> {
+        // Serialize the entries
+        let mut state = String::new();
+        for (key, Entry { since, event_id }) in &self.entries {
+            state.push_str(&format!("{since} {event_id} {key}\n"));
+        }
+
+        // Atomically replace the state file
+        private::replace(&self.path, state.as_bytes())
+    }
+}
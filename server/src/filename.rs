@@ -0,0 +1,220 @@
+//! Encoding and validation of attachment names and IPC file names
+
+pub use sendmatrix_common::filename::sanitize;
+use std::{
+    ffi::OsStr,
+    io::{Error, ErrorKind},
+};
+
+/// The kind of an IPC message file as determined by its file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A legacy `.txt` plaintext message
+    Plaintext,
+    /// A legacy `.markdown` message
+    Markdown,
+    /// A legacy `.raw` attachment
+    Raw,
+    /// A `.msg` envelope
+    Envelope,
+    /// A `.sealed` envelope that is encrypted to the server key
+    Sealed,
+}
+impl Kind {
+    /// Classifies an IPC file name by its extension, or returns `None` if the file is no IPC message
+    ///
+    /// # Note
+    /// Files with non-ascii names and dotfiles without a stem are no IPC messages; extensions are matched
+    /// case-insensitively
+    pub fn classify(filename: &OsStr) -> Option<Self> {
+        // Get the extension of an ascii name
+        let filename = filename.to_str().filter(|filename| filename.is_ascii())?;
+        let (stem, extension) = filename.rsplit_once('.')?;
+        let true = !stem.is_empty() else {
+            return None;
+        };
+
+        // Match the extension
+        match extension {
+            ext if ext.eq_ignore_ascii_case("txt") => Some(Self::Plaintext),
+            ext if ext.eq_ignore_ascii_case("markdown") => Some(Self::Markdown),
+            ext if ext.eq_ignore_ascii_case("raw") => Some(Self::Raw),
+            ext if ext.eq_ignore_ascii_case("msg") => Some(Self::Envelope),
+            ext if ext.eq_ignore_ascii_case("sealed") => Some(Self::Sealed),
+            _ => None,
+        }
+    }
+}
+
+/// Gets the attachment name of a legacy `.raw` message, e.g. `image.jpg` for `image.jpg.raw` or `IMAGE.JPG.RAW`
+///
+/// # Note
+/// Non-ascii names are percent-encoded, e.g. `%C3%9Cbersicht.pdf.raw` contains `Übersicht.pdf`. The extension is
+/// matched case-insensitively like in [`Kind::classify`], and the decoded name is validated via [`sanitize`].
+pub fn raw_name(filename: &OsStr) -> Result<String, Error> {
+    // Strip the extension
+    let invalid =
+        || Error::new(ErrorKind::InvalidData, format!("invalid raw message name: {}", filename.to_string_lossy()));
+    let filename = filename.to_str().filter(|filename| filename.is_ascii()).ok_or_else(invalid)?;
+    let (name, extension) = filename.rsplit_once('.').ok_or_else(invalid)?;
+    let true = extension.eq_ignore_ascii_case("raw") else {
+        return Err(invalid());
+    };
+
+    // Decode and validate the name
+    sanitize(&decode(name))
+}
+
+/// Decodes a percent-encoded on-disk file name into the attachment name, e.g. `Bericht_%C3%9Cbersicht.pdf` into
+/// `Bericht_Übersicht.pdf`
+///
+/// # Note
+/// Invalid escape sequences are kept as they are; if the decoded name is not valid UTF-8, the name is returned as it is
+pub fn decode(filename: &str) -> String {
+    // Decode all valid escape sequences
+    let (mut decoded, mut bytes) = (Vec::with_capacity(filename.len()), filename.as_bytes());
+    while let Some((&byte, rest)) = bytes.split_first() {
+        let escaped = match rest {
+            [high, low, ..] if byte == b'%' => char::from(*high).to_digit(16).zip(char::from(*low).to_digit(16)),
+            _ => None,
+        };
+        match escaped {
+            Some((high, low)) => {
+                decoded.push((high << 4 | low) as u8);
+                bytes = rest.get(2..).unwrap_or_default();
+            }
+            None => {
+                decoded.push(byte);
+                bytes = rest;
+            }
+        }
+    }
+
+    // Validate the decoded name
+    match String::from_utf8(decoded) {
+        Ok(decoded) => decoded,
+        Err(_) => filename.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, raw_name, sanitize, Kind};
+    use proptest::prelude::*;
+    use sendmatrix_common::filename::NAME_MAX;
+    use std::ffi::OsStr;
+
+    /// Percent-encodes an attachment name like the producers do
+    fn encode(name: &str) -> String {
+        let encode_byte = |byte: &u8| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'_' | b'-' => char::from(*byte).to_string(),
+            byte => format!("%{byte:02X}"),
+        };
+        name.as_bytes().iter().map(encode_byte).collect()
+    }
+
+    #[test]
+    fn odd_names() {
+        // Names that are only an extension, have no stem or mix the case of the extension
+        let names = [
+            "",
+            ".",
+            "..",
+            ".raw",
+            "..raw",
+            "raw",
+            ".RAW",
+            "x.",
+            "x.RAW",
+            "x.Raw",
+            "%",
+            "%.raw",
+            "%2E.raw",
+            "%2F.raw",
+            "%ZZ.raw",
+            "-x.raw",
+            "x/y.raw",
+            ".txt",
+            "x.tmp",
+            "x.msg.tmp",
+            "x.sealed.msg",
+        ];
+        for name in names {
+            let kind = Kind::classify(OsStr::new(name));
+            let raw = raw_name(OsStr::new(name));
+            assert!(raw.is_err() || kind == Some(Kind::Raw), "{name}: {kind:?} {raw:?}");
+        }
+
+        // Check the known classifications
+        assert_eq!(Kind::classify(OsStr::new(".raw")), None);
+        assert_eq!(Kind::classify(OsStr::new("x.tmp")), None);
+        assert_eq!(Kind::classify(OsStr::new("x.RAW")), Some(Kind::Raw));
+        assert_eq!(Kind::classify(OsStr::new("x.sealed.msg")), Some(Kind::Envelope));
+        assert_eq!(raw_name(OsStr::new("x.RAW")).ok().as_deref(), Some("x"));
+        assert_eq!(raw_name(OsStr::new("IMAGE.JPG.RAW")).ok().as_deref(), Some("IMAGE.JPG"));
+        assert!(raw_name(OsStr::new("x.raw.txt")).is_err());
+        assert!(raw_name(OsStr::new("%2F.raw")).is_err());
+        assert!(raw_name(OsStr::new("..raw")).is_err());
+        assert_eq!(raw_name(OsStr::new("%ZZ.raw")).ok().as_deref(), Some("%ZZ"));
+    }
+
+    proptest! {
+        #[test]
+        fn classify_never_panics(name in any::<String>()) {
+            let kind = Kind::classify(OsStr::new(&name));
+            prop_assert!(kind.is_none() || name.is_ascii());
+        }
+
+        #[test]
+        fn classify_extensions(stem in "[a-zA-Z0-9 ._%-]{1,32}", extension in "(?i-u)(txt|markdown|raw|msg|sealed)") {
+            let expected = match extension.to_ascii_lowercase().as_str() {
+                "txt" => Kind::Plaintext,
+                "markdown" => Kind::Markdown,
+                "raw" => Kind::Raw,
+                "msg" => Kind::Envelope,
+                _ => Kind::Sealed,
+            };
+            let name = format!("{stem}.{extension}");
+            prop_assert_eq!(Kind::classify(OsStr::new(&name)), Some(expected));
+        }
+
+        #[test]
+        fn raw_name_never_panics(name in any::<String>()) {
+            // Every accepted name must be a valid attachment name
+            if let Ok(raw_name) = raw_name(OsStr::new(&name)) {
+                prop_assert_eq!(sanitize(&raw_name).ok(), Some(raw_name));
+            }
+        }
+
+        #[test]
+        fn raw_name_is_consistent(name in "[ -~]{0,32}", extension in "(?i-u)(txt|markdown|raw|msg|sealed)") {
+            // Every name that is classified as raw message is either parsed or rejected as invalid data
+            let filename = format!("{name}.{extension}");
+            let kind = Kind::classify(OsStr::new(&filename));
+            match raw_name(OsStr::new(&filename)) {
+                Ok(_) => prop_assert_eq!(kind, Some(Kind::Raw)),
+                Err(e) => prop_assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            }
+        }
+
+        #[test]
+        fn raw_name_roundtrip(name in r"[^\p{Cc}/\\.-][^\p{Cc}/\\]{0,50}", extension in "(?i-u)raw") {
+            let filename = format!("{}.{extension}", encode(&name));
+            prop_assert_eq!(raw_name(OsStr::new(&filename)).ok(), Some(name));
+        }
+
+        #[test]
+        fn decode_never_panics(name in any::<String>()) {
+            let decoded = decode(&name);
+            prop_assert!(name.contains('%') || decoded == name);
+        }
+
+        #[test]
+        fn sanitize_is_idempotent(name in any::<String>()) {
+            if let Ok(sanitized) = sanitize(&name) {
+                prop_assert!(sanitized.len() <= NAME_MAX);
+                prop_assert_eq!(sanitize(&sanitized).ok(), Some(sanitized));
+            }
+        }
+    }
+}
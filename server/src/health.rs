@@ -0,0 +1,154 @@
+//! Health reporting via a status file for container orchestration
+
+use crate::{config::Config, private, time};
+use std::{
+    fs,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+/// The health status of the server
+///
+/// # Format
+/// The status is stored as `key=value` lines, e.g.
+/// ```text
+/// last-poll=1760000000
+/// last-send=1759999990
+/// queue=3
+/// last-error=1759990000 failed to send message
+/// failed=4F1C2A9E-0B7D-4C3A-9E21-6D5B8A7F3C10.msg
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Status {
+    /// The UNIX timestamp of the end of the last poll loop
+    pub last_poll: u64,
+    /// The UNIX timestamp of the last successful send if any
+    pub last_send: Option<u64>,
+    /// The amount of pending messages
+    pub queue: usize,
+    /// The UNIX timestamp and the description of the last error if any
+    pub last_error: Option<(u64, String)>,
+    /// The IPC file name of the message that failed to be processed and has not been retried yet if any
+    pub failed: Option<String>,
+}
+impl Status {
+    /// Encodes the status
+    pub fn encode(&self) -> String {
+        let mut status = format!("last-poll={}\n", self.last_poll);
+        if let Some(last_send) = self.last_send {
+            status.push_str(&format!("last-send={last_send}\n"));
+        }
+        status.push_str(&format!("queue={}\n", self.queue));
+        if let Some((time, error)) = &self.last_error {
+            status.push_str(&format!("last-error={time} {}\n", error.replace(['\n', '\r'], " ")));
+        }
+        if let Some(failed) = &self.failed {
+            status.push_str(&format!("failed={failed}\n"));
+        }
+        status
+    }
+
+    /// Decodes a status
+    pub fn decode(status: &str) -> Result<Self, Error> {
+        let mut this = Self::default();
+        for line in status.lines() {
+            // Parse the line
+            let invalid = || Error::new(ErrorKind::InvalidData, format!("invalid health status: {line}"));
+            let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+            match key {
+                "last-poll" => this.last_poll = value.parse().map_err(|_| invalid())?,
+                "last-send" => this.last_send = Some(value.parse().map_err(|_| invalid())?),
+                "queue" => this.queue = value.parse().map_err(|_| invalid())?,
+                "last-error" => {
+                    let (time, error) = value.split_once(' ').unwrap_or((value, ""));
+                    let time = time.parse().map_err(|_| invalid())?;
+                    this.last_error = Some((time, error.to_string()));
+                }
+                "failed" => this.failed = Some(value.to_string()),
+                _ => (/* ignore unknown keys for forward compatibility */),
+            }
+        }
+        Ok(this)
+    }
+}
+
+/// Reports the health of the server via a status file
+#[derive(Debug)]
+pub struct Health {
+    /// The path to the status file
+    path: PathBuf,
+    /// The current status
+    status: Status,
+}
+impl Health {
+    /// The subdirectory of the IPC directory that contains the status file
+    ///
+    /// # Note
+    /// The subdirectory is only accessible by the server, so that producers cannot fake the status
+    pub const SUBDIR: &'static str = "health";
+    /// The status file name within the subdirectory
+    const STATE_FILE: &'static str = "health.state";
+
+    /// Creates a new health reporter and keeps the last send, the last error and the failed message of the previous
+    /// run if any
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        // Load the previous status if any
+        let path = private::dir(config, Self::SUBDIR)?.join(Self::STATE_FILE);
+        let previous = fs::read_to_string(&path).ok().and_then(|status| Status::decode(&status).ok());
+        let Status { last_send, last_error, failed, .. } = previous.unwrap_or_default();
+        Ok(Self { path, status: Status { last_send, last_error, failed, ..Status::default() } })
+    }
+
+    /// Records the result of a poll loop together with the message that failed if any, the last successful send and
+    /// the queue depth
+    pub fn report(
+        &mut self,
+        result: &Result<(), Error>,
+        failed: Option<&Path>,
+        last_send: Option<u64>,
+        queue: usize,
+    ) -> Result<(), Error> {
+        // Update the status
+        let now = time::now();
+        self.status.last_poll = now;
+        self.status.last_send = last_send.or(self.status.last_send);
+        self.status.queue = queue;
+        if let Err(e) = result {
+            self.status.last_error = Some((now, e.to_string()));
+            self.status.failed = failed.and_then(Path::file_name).map(|name| name.to_string_lossy().into_owned());
+        }
+
+        // Atomically replace the status file
+        private::replace(&self.path, self.status.encode().as_bytes())
+    }
+
+    /// Checks if a message is retried, i.e. if it is the message that failed last, and forgets the failure if so
+    pub fn is_retry(&mut self, message: &Path) -> bool {
+        let name = message.file_name().map(|name| name.to_string_lossy());
+        let true = name.is_some_and(|name| self.status.failed.as_deref() == Some(&*name)) else {
+            return false;
+        };
+        self.status.failed = None;
+        true
+    }
+
+    /// Checks the status file of a running server
+    ///
+    /// # Note
+    /// The server is unhealthy if its last poll loop is older than `HEALTH_MAX_AGE` seconds or has failed
+    pub fn check(config: &Config) -> Result<Status, Error> {
+        // Load the status
+        let path = Path::new(&config.IPC_PATH).join(Self::SUBDIR).join(Self::STATE_FILE);
+        let status = Status::decode(&fs::read_to_string(path)?)?;
+
+        // Check the status
+        let age = time::now().saturating_sub(status.last_poll);
+        if age > config.HEALTH_MAX_AGE {
+            return Err(Error::new(ErrorKind::TimedOut, format!("last poll loop was {age} seconds ago")));
+        }
+        if let Some((time, error)) = status.last_error.as_ref().filter(|(time, _)| *time >= status.last_poll) {
+            return Err(Error::other(format!("last poll loop failed at {time}: {error}")));
+        }
+        Ok(status)
+    }
+}
@@ -0,0 +1,55 @@
+//! HTML helpers
+
+/// Escapes a text so that it can be embedded into HTML
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char_ in text.chars() {
+        match char_ {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            char_ => escaped.push(char_),
+        }
+    }
+    escaped
+}
+
+/// Derives a plaintext fallback from HTML by stripping all tags and decoding the basic entities
+///
+/// # Note
+/// This is not a full HTML parser; it is only meant to produce a readable fallback for simple generated HTML
+pub fn to_plaintext(html: &str) -> String {
+    // Strip all tags and insert line breaks for line-breaking elements
+    let (mut text, mut rest) = (String::with_capacity(html.len()), html);
+    while let Some((before, after)) = rest.split_once('<') {
+        text.push_str(before);
+        let (tag, after) = after.split_once('>').unwrap_or((after, ""));
+        let name =
+            tag.trim_start_matches('/').split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or_default();
+        if ["br", "p", "div", "li", "tr", "h1", "h2", "h3", "h4", "h5", "h6"]
+            .iter()
+            .any(|n| name.eq_ignore_ascii_case(n))
+        {
+            text.push('\n');
+        }
+        rest = after;
+    }
+    text.push_str(rest);
+
+    // Decode the basic entities
+    let text = text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&#39;", "'");
+    let text = text.replace("&nbsp;", " ").replace("&amp;", "&");
+
+    // Collapse consecutive empty lines
+    let mut plaintext = String::with_capacity(text.len());
+    for line in text.lines().map(str::trim_end) {
+        if line.is_empty() && (plaintext.is_empty() || plaintext.ends_with("\n\n")) {
+            continue;
+        }
+        plaintext.push_str(line);
+        plaintext.push('\n');
+    }
+    plaintext.trim_end().to_string()
+}
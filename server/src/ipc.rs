@@ -1,12 +1,24 @@
 //! The IPC server
 
-use crate::{config::Config, message::Message};
+use crate::{
+    audit,
+    auth::Signature,
+    config::Config,
+    envelope::{Envelope, Header, Metadata, Payload},
+    filename::{self, Kind},
+    log::{self, Correlation},
+    message::{Attachment, Message},
+    private,
+    seal::Unsealer,
+    time,
+};
 use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
     fs::{self, File},
-    io::{Error, ErrorKind, Read},
+    io::{self, Error, ErrorKind, Read, Seek, SeekFrom},
     path::{Path, PathBuf},
     thread,
-    time::Duration,
+    time::{Duration, UNIX_EPOCH},
 };
 
 /// The IPC server
@@ -14,37 +26,53 @@ use std::{
 pub struct IpcServer<'a> {
     /// The config
     config: &'a Config,
-    /// The pending IPC messages
-    pending: Vec<PathBuf>,
+    /// The pending IPC messages, ordered by descending priority and then by the order they have been seen in
+    pending: VecDeque<PathBuf>,
+    /// The known IPC messages together with their header metadata and the sequence number they have been seen with
+    known: BTreeMap<PathBuf, (Metadata, u64)>,
+    /// The sequence number for the next newly seen IPC message
+    sequence: u64,
+    /// The files with non-ascii names that have been reported already
+    ignored: BTreeSet<PathBuf>,
+    /// The decryption of sealed messages
+    unsealer: Unsealer,
+    /// The directory for the private copies of signed messages
+    spool: PathBuf,
+    /// The correlation ID of the currently processed message for logging
+    correlation: Option<Correlation>,
 }
 impl<'a> IpcServer<'a> {
     /// The file system poll interval
     const POLL_INTERVAL: Duration = Duration::from_secs(3);
     /// The maximum plaintext/markdown message size
     const TEXT_SIZE_MAX: usize = 4096;
-    /// The maximum file size for attachments
-    const FILE_SIZE_MAX: usize = 2 * 1024 * 1024;
+    /// The subdirectory of the IPC directory where signed messages are copied to while they are processed
+    pub const SPOOL_SUBDIR: &'static str = "signed";
 
     /// Creates a new server
     pub fn new(config: &'a Config) -> Result<Self, Error> {
-        // Initialize self and poll one time to check if everything works as expected
-        let mut this = Self { config, pending: Vec::new() };
+        // Audit the IPC directory, initialize self and poll one time to check if everything works as expected
+        audit::audit(config)?;
+        let unsealer = Unsealer::new(config)?;
+        let spool = private::dir(config, Self::SPOOL_SUBDIR)?;
+        private::clear(&spool)?;
+        let (pending, known, ignored) = (VecDeque::new(), BTreeMap::new(), BTreeSet::new());
+        let mut this = Self { config, pending, known, sequence: 0, ignored, unsealer, spool, correlation: None };
         let _ = this.has_message()?;
 
         // Print status and return instance
-        eprintln!("*> IPC backlog: {}", this.pending.len());
+        log::info!("IPC backlog: {}", this.pending.len());
         Ok(this)
     }
 
-    /// Checks if there are pending messages; scans the IPC directory if necessary
+    /// Checks if there are pending messages; rescans the IPC directory
+    ///
+    /// # Note
+    /// The directory is rescanned on every call, so that a new message with a higher priority is sent before the
+    /// remaining backlog. The header metadata is only read once per message.
     pub fn has_message(&mut self) -> Result<bool, Error> {
-        // Early-abort if we still have some pending messages
-        let true = self.pending.is_empty() else {
-            return Ok(true);
-        };
-
         // Collect all pending messages; ignore non-file entries, symlinks etc.
-        self.pending.clear();
+        let (now, mut pending, mut known, mut ignored) = (time::now(), Vec::new(), BTreeMap::new(), BTreeSet::new());
         'read_dir: for maybe_entry in fs::read_dir(&self.config.IPC_PATH)? {
             // Get the entry and ensure it's a file
             let entry = maybe_entry?;
@@ -52,117 +80,385 @@ impl<'a> IpcServer<'a> {
                 continue 'read_dir;
             };
 
-            // Ignore files with non-ascii names
+            // Report files with non-ascii names once, since their names must be percent-encoded
             let path = entry.path();
-            match path.file_name() {
-                Some(filename) if filename.is_ascii() => (/* valid */),
-                _ => continue 'read_dir,
+            if !path.file_name().is_some_and(|filename| filename.is_ascii()) {
+                if !self.ignored.contains(&path) {
+                    log::warning!("Ignoring IPC file with non-ascii name: {}", path.display());
+                }
+                ignored.insert(path);
+                continue 'read_dir;
+            }
+
+            // Ignore files that are no IPC messages
+            let true = Self::is_message(&path) else {
+                continue 'read_dir;
             };
 
-            // Ignore files that don't end with txt, markdown or raw
-            match path.extension() {
-                Some(ext) if ext.eq_ignore_ascii_case("txt") => (/* valid */),
-                Some(ext) if ext.eq_ignore_ascii_case("markdown") => (/* valid */),
-                Some(ext) if ext.eq_ignore_ascii_case("raw") => (/* valid */),
-                _ => continue 'read_dir,
-            }
+            // Get the metadata of known messages or read the metadata of new messages
+            let (metadata, sequence) = match self.known.remove(&path) {
+                Some(known) => known,
+                None => {
+                    self.sequence = self.sequence.saturating_add(1);
+                    (self.read_metadata(&path), self.sequence)
+                }
+            };
 
-            // Store path
-            self.pending.push(path);
+            // Defer messages that are not due yet
+            let is_due = metadata.not_before.is_none_or(|not_before| not_before <= now);
+            if is_due {
+                pending.push((metadata.priority, sequence, path.clone()));
+            }
+            known.insert(path, (metadata, sequence));
         }
 
+        // Order the messages by descending priority and then in the order they have been seen in
+        pending.sort_by(|(a_priority, a_sequence, _), (b_priority, b_sequence, _)| {
+            b_priority.cmp(a_priority).then(a_sequence.cmp(b_sequence))
+        });
+        self.pending = pending.into_iter().map(|(_, _, path)| path).collect();
+
+        // Keep track of the known messages so that we don't have to reread them on every poll
+        self.known = known;
+        self.ignored = ignored;
+
         // Return if we have pending messages or not
         Ok(!self.pending.is_empty())
     }
 
+    /// The amount of pending messages
+    pub fn queue_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The message that is currently processed if any, i.e. that has been read but not completed yet
+    pub fn current(&self) -> Option<&Path> {
+        self.correlation.as_ref().and(self.pending.front()).map(PathBuf::as_path)
+    }
+
     /// Gets the next pending message
     ///
     /// # Important
-    /// This function blocks if there is no pending message available
-    pub fn next_message(&mut self) -> Result<Message, Error> {
-        // Poll until we have messages
-        while !self.has_message()? {
-            // Yield some time if there are no pending messages
+    /// If there is no pending message available, this function blocks for one poll interval and returns `None`
+    pub fn next_message(&mut self) -> Result<Option<Envelope>, Error> {
+        // Yield some time if there are no pending messages
+        if !self.has_message()? {
             thread::sleep(Self::POLL_INTERVAL);
+            return Ok(None);
         }
 
         // Get the next messages
-        // Note: This is safe since the while-loop above ensures that `self.files` is not empty
-        #[allow(clippy::expect_used)]
-        let message = self.pending.first().expect("no pending IPC message after successful polling");
-        match message.extension() {
-            Some(ext) if ext.eq_ignore_ascii_case("txt") => {
+        // Note: This is safe since the check above ensures that `self.pending` is not empty
+        #[allow(clippy::expect_used, reason = "see note")]
+        let message = self.pending.front().expect("no pending IPC message after successful polling");
+        // Correlate all records with the IPC file name until the message has been completed
+        // Note: The previous correlation must be dropped first, so that it does not restore its predecessor later
+        let id = message.file_name().map(|name| name.to_string_lossy().into_owned());
+        self.correlation = None;
+        self.correlation = Some(log::correlate(id));
+        match self.read_envelope(message) {
+            Ok(envelope) => {
+                log::debug!("Processing {} message", envelope.message.kind());
+                Ok(Some(envelope))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                // The message has been cancelled in the meantime
+                self.pending.pop_front();
+                self.correlation = None;
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Marks the currently pending message as completed and removes it from the queue
+    pub fn complete_message(&mut self) -> Result<(), Error> {
+        // Clear the buffer and delete the currently pending file
+        let Some(pending) = self.pending.front() else {
+            // Indicate that there was no pending message
+            return Err(Error::from(ErrorKind::NotFound));
+        };
+
+        // Unlink the file if it has not been removed in the meantime
+        self.release(pending)?;
+        match fs::remove_file(pending) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => (/* file has been removed */),
+        }
+        self.pending.pop_front();
+        self.correlation = None;
+        Ok(())
+    }
+
+    /// Moves the currently pending message into the given subdirectory of the IPC directory and removes it from the
+    /// queue
+    pub fn archive_message(&mut self, subdir: &str) -> Result<(), Error> {
+        // Get the currently pending file
+        let Some(pending) = self.pending.front() else {
+            // Indicate that there was no pending message
+            return Err(Error::from(ErrorKind::NotFound));
+        };
+        let Some(filename) = pending.file_name() else {
+            // Indicate that the path is invalid
+            return Err(Error::from(ErrorKind::InvalidInput));
+        };
+
+        // Move the file; sealed messages are moved as they are, so they remain encrypted
+        self.release(pending)?;
+        let dir = Path::new(&self.config.IPC_PATH).join(subdir);
+        fs::create_dir_all(&dir)?;
+        fs::rename(pending, dir.join(filename))?;
+        log::debug!("Moved message to {subdir}");
+        self.pending.pop_front();
+        self.correlation = None;
+        Ok(())
+    }
+
+    /// Checks if a path looks like an IPC message, i.e. has an ascii name and a known extension
+    pub fn is_message(path: &Path) -> bool {
+        path.file_name().and_then(Kind::classify).is_some()
+    }
+
+    /// Reads an IPC message from the given path
+    ///
+    /// # Important
+    /// The path must be validated with [`Self::is_message`] before. Sealed messages are decrypted and signed messages
+    /// are copied into a spool file which is referenced by the envelope and must be removed via [`Self::release`] once
+    /// the envelope has been processed; this is done automatically for the currently pending message.
+    pub fn read_envelope(&self, message: &Path) -> Result<Envelope, Error> {
+        // Get the submission time and the owner from the handle that signed messages are copied from
+        let mut file = File::open(message)?;
+        let file_metadata = file.metadata()?;
+        let modified = file_metadata.modified()?;
+        let received = modified.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or_default();
+
+        // Decrypt sealed messages, copy signed messages and decode the message
+        let mut envelope = match Self::kind(message)? {
+            Kind::Sealed => Self::decode_message(&self.unsealer.unseal(message)?)?,
+            Kind::Envelope if Self::is_signed(&mut file)? => Self::decode_message(&self.copy(message, &mut file)?)?,
+            _ => Self::decode_message(message)?,
+        };
+        envelope.metadata.received = received;
+        envelope.metadata.owner = Self::owner(&file_metadata);
+        Ok(envelope)
+    }
+
+    /// Removes the spool file of a sealed or signed message that has been read via [`Self::read_envelope`]
+    pub fn release(&self, message: &Path) -> Result<(), Error> {
+        // Remove the decrypted envelope
+        self.unsealer.remove(message)?;
+
+        // Remove the private copy
+        let Some(filename) = message.file_name() else {
+            return Ok(());
+        };
+        match fs::remove_file(self.spool.join(filename)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks if an envelope has a signature header
+    ///
+    /// # Note
+    /// Envelopes with an invalid header are not signed, so that they are decoded and rejected as they are
+    fn is_signed(file: &mut File) -> Result<bool, Error> {
+        let mut header = Vec::new();
+        file.take(Envelope::HEADER_SIZE_MAX.saturating_add(2) as u64).read_to_end(&mut header)?;
+        Ok(Header::decode(&header).is_ok_and(|(header, _)| header.signature.is_some()))
+    }
+
+    /// Copies an opened signed message into the spool directory and returns the path of the copy
+    ///
+    /// # Note
+    /// The signature is verified over the copy and its attachments are sent from the copy, so that the producer cannot
+    /// modify or replace the message once it has been verified
+    fn copy(&self, message: &Path, file: &mut File) -> Result<PathBuf, Error> {
+        // Remove a stale copy
+        let Some(filename) = message.file_name() else {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        };
+        self.release(message)?;
+
+        // Copy the message
+        let copy = self.spool.join(filename);
+        file.seek(SeekFrom::Start(0))?;
+        io::copy(file, &mut private::create(&copy)?)?;
+        Ok(copy)
+    }
+
+    /// Gets the UID of the owner of an IPC message
+    #[cfg(unix)]
+    fn owner(file_metadata: &fs::Metadata) -> Option<u32> {
+        use std::os::unix::fs::MetadataExt;
+        Some(file_metadata.uid())
+    }
+    /// Gets the UID of the owner of an IPC message
+    ///
+    /// # Note
+    /// File ownership is only supported on unix, so there is never an owner on other platforms
+    #[cfg(not(unix))]
+    fn owner(_file_metadata: &fs::Metadata) -> Option<u32> {
+        None
+    }
+
+    /// Decodes an IPC message according to its file extension
+    fn decode_message(message: &Path) -> Result<Envelope, Error> {
+        match Self::kind(message)? {
+            Kind::Plaintext => {
                 // A .txt-file contains a plaintext message
-                let contents = self.read_message(message, Self::TEXT_SIZE_MAX)?;
-                Ok(Message::Plaintext { text: contents })
+                let contents = Self::read_message(message, 0, Self::TEXT_SIZE_MAX)?;
+                Ok(Envelope::new(Message::Plaintext { text: contents }))
             }
-            Some(ext) if ext.eq_ignore_ascii_case("markdown") => {
+            Kind::Markdown => {
                 // A .markdown-file contains a markdown message
-                let contents = self.read_message(message, Self::TEXT_SIZE_MAX)?;
-                Ok(Message::Markdown { markdown: contents })
+                let contents = Self::read_message(message, 0, Self::TEXT_SIZE_MAX)?;
+                Ok(Envelope::new(Message::Markdown { markdown: contents }))
             }
-            Some(ext) if ext.eq_ignore_ascii_case("raw") => {
+            Kind::Raw => {
                 // A .raw-file is a binary attachment, e.g. `image.jpg.raw` contains the binary attachment `image.jpg`
-                // Get the real file name
-                let name = match message.file_name() {
-                    Some(name) if name.is_ascii() => {
-                        // Note: This should be safe because `self.poll_messages` validates the file name
-                        #[allow(clippy::expect_used)]
-                        let name = name.to_str().expect("invalid file name for pending IPC message");
-                        // Note: This is safe since we have checked that the file ends with .raw
-                        #[allow(clippy::expect_used)]
-                        name.strip_suffix(".raw").expect("cannot strip suffix .raw from string ending with .raw")
-                    }
-                    _ => {
-                        // Note: This should be safe because `self.poll_messages` validates the file name
-                        #[allow(clippy::unreachable)]
-                        (unreachable!("invalid file name for pending IPC message"))
-                    }
+                let name = filename::raw_name(message.file_name().unwrap_or_default())?;
+
+                // Reference the contents
+                let contents = Self::attachment(message, 0)?;
+                Ok(Envelope::new(Message::Raw { name, contents }))
+            }
+            Kind::Envelope => {
+                // A .msg-file contains an envelope with metadata and the message payload
+                let header = Self::read_header(message, Envelope::HEADER_SIZE_MAX.saturating_add(2))?;
+                let (header, payload_offset) = Header::decode(&header)?;
+
+                // Reference the payload of attachments, but load the payload of text messages
+                let payload_offset = payload_offset as u64;
+                let payload = match header.is_attachment() {
+                    true => Payload::Attachment(Self::attachment(message, payload_offset)?),
+                    false => Self::text(message, payload_offset, &header.attachments)?,
                 };
 
-                // Get the contents
-                let contents = self.read_message(message, Self::FILE_SIZE_MAX)?;
-                Ok(Message::Raw { name: name.to_string(), contents })
+                // Reference the signed regions, i.e. everything except the signature line
+                let signature = match &header.signature {
+                    Some((mac, line)) => Some(Signature {
+                        producer: header.producer.clone(),
+                        signed_at: header.signed_at,
+                        nonce: header.nonce.clone(),
+                        mac: mac.clone(),
+                        signed: vec![
+                            Attachment { path: message.to_path_buf(), offset: 0, len: line.start },
+                            Self::attachment(message, line.end)?,
+                        ],
+                    }),
+                    None => None,
+                };
+                let mut envelope = Envelope::decode(header, payload)?;
+                envelope.metadata.signature = signature;
+                Ok(envelope)
             }
-            _ => {
-                // Note: This should be safe because `self.poll_messages` validates the  extensions
-                #[allow(clippy::unreachable)]
-                (unreachable!("invalid file extension for pending IPC message"))
+            Kind::Sealed => {
+                // Sealed messages must be decrypted before they can be decoded
+                Err(Error::new(ErrorKind::InvalidInput, "cannot decode sealed message"))
             }
         }
     }
 
-    /// Marks the currently pending message as completed and removes it from the queue
-    pub fn complete_message(&mut self) -> Result<(), Error> {
-        // Clear the buffer and delete the currently pending file
-        let Some(pending) = self.pending.first() else {
-            // Indicate that there was no pending message
-            return Err(Error::from(ErrorKind::NotFound));
+    /// Classifies an IPC message by its file name
+    fn kind(message: &Path) -> Result<Kind, Error> {
+        match message.file_name().and_then(Kind::classify) {
+            Some(kind) => Ok(kind),
+            None => Err(Error::new(ErrorKind::InvalidInput, format!("not an IPC message: {}", message.display()))),
+        }
+    }
+
+    /// Reads the header metadata of an IPC message to schedule it
+    ///
+    /// # Note
+    /// Legacy messages and messages with an unreadable header get the default metadata, so that they are due
+    /// immediately and any error is reported once the message is processed
+    fn read_metadata(&self, message: &Path) -> Metadata {
+        // Only envelopes can have metadata; sealed envelopes are decrypted as far as necessary
+        let limit = Envelope::HEADER_SIZE_MAX.saturating_add(2);
+        let header = match Self::kind(message) {
+            Ok(Kind::Envelope) => Self::read_header(message, limit),
+            Ok(Kind::Sealed) => self.unsealer.header(message, limit),
+            _ => return Metadata::default(),
         };
 
-        // Unlink the file
-        fs::remove_file(pending)?;
-        // Note: This is safe since we have ensured that the vector has a first element
-        self.pending.swap_remove(0);
-        Ok(())
+        // Decode the header
+        let Ok(header) = header else {
+            return Metadata::default();
+        };
+        match Header::decode(&header) {
+            Ok((header, _)) => header.metadata,
+            Err(_) => Metadata::default(),
+        }
+    }
+
+    /// Reads up to `limit` bytes from the start of an IPC message
+    fn read_header(message: &Path, limit: usize) -> Result<Vec<u8>, Error> {
+        let mut header = Vec::new();
+        File::open(message)?.take(limit as u64).read_to_end(&mut header)?;
+        Ok(header)
+    }
+
+    /// References the attachment that starts at the given offset within an IPC message
+    fn attachment(entry: &Path, offset: u64) -> Result<Attachment, Error> {
+        let len = fs::metadata(entry)?.len().saturating_sub(offset);
+        Ok(Attachment { path: entry.to_path_buf(), offset, len })
+    }
+
+    /// Loads the text that starts at the given offset within an IPC message and references the attachments that
+    /// follow it
+    fn text(entry: &Path, offset: u64, attachments: &[(u64, String)]) -> Result<Payload, Error> {
+        // Split the payload into the text and the attachments
+        let payload_len = fs::metadata(entry)?.len().saturating_sub(offset);
+        let attachments_len = attachments.iter().try_fold(0u64, |sum, (len, _)| sum.checked_add(*len));
+        let Some(text_len) = attachments_len.and_then(|attachments_len| payload_len.checked_sub(attachments_len))
+        else {
+            return Err(Error::new(ErrorKind::InvalidData, "attachments exceed the message size"));
+        };
+
+        // Reference the attachments in order
+        let mut regions = Vec::new();
+        let mut attachment_offset = offset.saturating_add(text_len);
+        for (len, name) in attachments {
+            regions
+                .push((name.clone(), Attachment { path: entry.to_path_buf(), offset: attachment_offset, len: *len }));
+            attachment_offset = attachment_offset.saturating_add(*len);
+        }
+
+        // Load the text
+        let text = Self::read_region(entry, offset, text_len, Self::TEXT_SIZE_MAX)?;
+        Ok(Payload::Text { text, attachments: regions })
+    }
+
+    /// Reads an IPC message starting at the given offset
+    fn read_message(entry: &Path, offset: u64, limit: usize) -> Result<Vec<u8>, Error> {
+        let len = fs::metadata(entry)?.len().saturating_sub(offset);
+        Self::read_region(entry, offset, len, limit)
     }
 
-    /// Reads an IPC message
-    fn read_message(&self, entry: &Path, limit: usize) -> Result<Vec<u8>, Error> {
-        // Validate the file size
-        let metadata = fs::metadata(entry)?;
-        if metadata.len() > limit as u64 {
-            // Indicate that the file size is unsupported
-            return Err(Error::from(ErrorKind::Unsupported));
+    /// Reads `len` bytes of an IPC message starting at the given offset
+    ///
+    /// # Note
+    /// Regions that exceed the limit or are truncated are invalid data, so that the message is quarantined
+    fn read_region(entry: &Path, offset: u64, len: u64, limit: usize) -> Result<Vec<u8>, Error> {
+        // Validate the region size
+        if len > limit as u64 {
+            // Indicate that the message is too large
+            return Err(Error::new(ErrorKind::InvalidData, format!("message text exceeds {limit} bytes")));
         }
 
         // Open the file
-        let len = metadata.len() as usize;
         let mut file = File::open(entry)?;
+        file.seek(SeekFrom::Start(offset))?;
 
         // Read the file
-        let mut contents = vec![0; len];
-        file.read_exact(&mut contents)?;
-        Ok(contents)
+        let mut contents = vec![0; len as usize];
+        match file.read_exact(&mut contents) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                Err(Error::new(ErrorKind::InvalidData, "message has been truncated"))
+            }
+            Err(e) => Err(e),
+            Ok(_) => Ok(contents),
+        }
     }
 }
@@ -0,0 +1,163 @@
+//! Structured logging with levels, an optional JSON line format, correlation IDs and redaction of secrets
+
+use crate::{config::Config, time};
+use std::{
+    cell::RefCell,
+    fmt::Arguments,
+    io::{self, Error, ErrorKind, Write},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+/// The global logger
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+thread_local! {
+    /// The correlation ID of the message that is currently processed, i.e. the name of its IPC file
+    static CORRELATION_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// A log level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Details that are only relevant for debugging
+    Debug,
+    /// Regular status information
+    Info,
+    /// Problems that don't stop the server
+    Warn,
+    /// Problems that stop the server
+    Error,
+}
+impl Level {
+    /// The level name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Debug => "debug",
+            Self::Info => "info",
+            Self::Warn => "warn",
+            Self::Error => "error",
+        }
+    }
+}
+impl FromStr for Level {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(Self::Debug),
+            "info" => Ok(Self::Info),
+            "warn" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("invalid log level: {s}"))),
+        }
+    }
+}
+
+/// The logger
+#[derive(Debug)]
+struct Logger {
+    /// The minimum level of logged records
+    level: Level,
+    /// Whether records are written as JSON lines
+    json: bool,
+    /// The secret values which are redacted from all records
+    secrets: Vec<String>,
+}
+impl Default for Logger {
+    fn default() -> Self {
+        Self { level: Level::Info, json: false, secrets: Vec::new() }
+    }
+}
+
+/// Initializes the global logger from the config
+///
+/// # Note
+/// Until the logger has been initialized, records are written with the default settings, i.e. level `info` as text
+pub fn init(config: &Config) -> Result<(), Error> {
+    // Parse the settings
+    let level = config.LOG_LEVEL.parse()?;
+    let json = match config.LOG_FORMAT.as_str() {
+        "text" => false,
+        "json" => true,
+        format => return Err(Error::new(ErrorKind::InvalidData, format!("invalid log format: {format}"))),
+    };
+
+    // Collect the secrets, i.e. the seal key and the individual producer keys
+    let producer_keys = config.PRODUCER_KEYS.0.split(';').filter_map(|entry| entry.split_once('=')).map(|(_, key)| key);
+    let secrets = [config.SEAL_PRIVATE_KEY.0.as_str()].into_iter().chain(producer_keys);
+    let secrets = secrets.map(str::trim).filter(|secret| !secret.is_empty()).map(str::to_string).collect();
+
+    // Set the logger
+    LOGGER
+        .set(Logger { level, json, secrets })
+        .map_err(|_| Error::new(ErrorKind::AlreadyExists, "logger is already initialized"))
+}
+
+/// Sets the correlation ID that is attached to all records until the returned guard is dropped
+pub fn correlate(id: Option<String>) -> Correlation {
+    let previous = CORRELATION_ID.with(|current| current.replace(id));
+    Correlation { previous }
+}
+
+/// A guard that restores the previous correlation ID once it is dropped
+#[derive(Debug)]
+pub struct Correlation {
+    /// The previous correlation ID
+    previous: Option<String>,
+}
+impl Drop for Correlation {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CORRELATION_ID.with(|current| current.replace(previous));
+    }
+}
+
+/// Writes a log record to stderr
+pub fn write(level: Level, args: Arguments) {
+    // Filter the record
+    let logger = LOGGER.get_or_init(Logger::default);
+    if level < logger.level {
+        return;
+    }
+
+    // Format and redact the message
+    let mut message = args.to_string();
+    for secret in &logger.secrets {
+        message = message.replace(secret, "<redacted>");
+    }
+
+    // Format the record
+    let id = CORRELATION_ID.with(|current| current.borrow().clone());
+    let marker = if level >= Level::Warn { "!>" } else { "*>" };
+    let record = match (logger.json, id) {
+        (true, id) => {
+            let record =
+                serde_json::json!({ "time": time::now(), "level": level.name(), "id": id, "message": message });
+            record.to_string()
+        }
+        (false, Some(id)) => format!("{marker} [{id}] {message}"),
+        (false, None) => format!("{marker} {message}"),
+    };
+
+    // Write the record; there is nothing we can do if stderr is not writable
+    let _ = writeln!(io::stderr().lock(), "{record}");
+}
+
+/// Logs a debug record
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Debug, format_args!($($arg)*)) };
+}
+/// Logs an info record
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Info, format_args!($($arg)*)) };
+}
+/// Logs a warning record
+macro_rules! warning {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Warn, format_args!($($arg)*)) };
+}
+/// Logs an error record
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Error, format_args!($($arg)*)) };
+}
+pub(crate) use {debug, error, info, warning};
@@ -13,42 +13,99 @@
 #![warn(clippy::allow_attributes_without_reason)]
 #![warn(clippy::cognitive_complexity)]
 
+mod audit;
+mod auth;
+mod commander;
 mod config;
+mod dedup;
+mod digest;
+mod dispatch;
+mod envelope;
+mod events;
+mod filename;
+mod health;
+mod html;
 mod ipc;
+mod log;
 mod matrix;
+mod media;
+mod mentions;
 mod message;
+mod metrics;
+mod priority;
+mod private;
+mod quota;
+mod seal;
+mod sink;
+mod time;
+mod upload;
 
-use crate::{config::Config, ipc::IpcServer, matrix::Matrix};
+use crate::{config::Config, dispatch::Dispatcher, health::Health, metrics::Metrics, seal::Unsealer};
+use std::{env, process};
 
 fn main() {
     // Load config
     // Note: We use expect here because if we cannot load the config we want to terminate
-    #[allow(clippy::expect_used)]
+    #[allow(clippy::expect_used, reason = "see note")]
     let config = Config::from_env().expect("failed to load config");
-    println!("*> Configuration: `{config:?}`");
 
-    // Create a matrix adapter
-    // Note: We use expect here because if we cannot create a matrix adapter we want to terminate
-    #[allow(clippy::expect_used)]
-    let matrix = Matrix::new(&config).expect("failed to initialize matrix adapter");
+    // Initialize the logger; secrets are redacted in the debug representation of the config
+    // Note: We use expect here because if we cannot initialize the logger we want to terminate
+    #[allow(clippy::expect_used, reason = "see note")]
+    log::init(&config).expect("failed to initialize logger");
+    log::debug!("Configuration: `{config:?}`");
 
-    // Create the server and proces messages
-    // Note: We use expect here because if we cannot create a server we want to terminate
-    #[allow(clippy::expect_used)]
-    let mut server = IpcServer::new(&config).expect("failed to start IPC server");
-    loop {
-        // Get the next message
-        // Note: We use expect here because if we cannot read IPC messages we want to terminate
-        #[allow(clippy::expect_used)]
-        let message = server.next_message().expect("failed to get next IPC message");
-
-        // Send message
-        // Note: We use expect here because if we cannot send a message we want to terminate
-        #[allow(clippy::expect_used)]
-        matrix.send(message).expect("failed to send message");
+    // Initialize the IPC directory, generate a seal key pair or check the health if requested
+    match env::args().nth(1).as_deref() {
+        None => (/* start the server */),
+        Some("--init-ipc") => {
+            // Note: We use expect here because if we cannot initialize the IPC directory we want to terminate
+            #[allow(clippy::expect_used, reason = "see note")]
+            audit::init(&config).expect("failed to initialize IPC directory");
+            return;
+        }
+        Some("--seal-keygen") => {
+            // Note: We use expect here because if we cannot generate a key pair we want to terminate
+            #[allow(clippy::expect_used, reason = "see note")]
+            let (private, public) = Unsealer::keygen().expect("failed to generate seal key pair");
+            println!("SEAL_PRIVATE_KEY={private}");
+            println!("SEAL_PUBLIC_KEY={public}");
+            return;
+        }
+        Some("healthcheck") => match Health::check(&config) {
+            Ok(status) => {
+                print!("{}", status.encode());
+                return;
+            }
+            Err(e) => {
+                log::error!("Unhealthy: {e}");
+                process::exit(1);
+            }
+        },
+        Some(arg) => {
+            // Note: We use panic here because we want to terminate on invalid arguments
+            #[allow(clippy::panic, reason = "see note")]
+            (panic!("invalid argument: {arg}"));
+        }
+    }
 
-        // Note: We use expect here because if we cannot process IPC messages we want to terminate
-        #[allow(clippy::expect_used)]
-        server.complete_message().expect("failed to finalize the processing of the IPC message");
+    // Create the metrics and the dispatcher
+    // Note: We log and terminate here because if we cannot create the dispatcher we want to terminate
+    let metrics = Metrics::new(&config);
+    let mut dispatcher = match Dispatcher::new(&config, &metrics) {
+        Ok(dispatcher) => dispatcher,
+        Err(e) => {
+            log::error!("Failed to start dispatcher: {e}");
+            process::exit(1);
+        }
+    };
+    loop {
+        // Process messages
+        // Note: We log and terminate here because if we cannot process IPC messages we want to terminate; the error is
+        // logged with the correlation ID of the failed message
+        if let Err(e) = dispatcher.tick() {
+            log::error!("Failed to process IPC messages: {e}");
+            process::exit(1);
+        }
     }
 }
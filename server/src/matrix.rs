@@ -1,70 +1,107 @@
 //! A outgoing adapter for matrix
 
-use crate::{config::Config, message::Message};
+use crate::{
+    commander::Commander, config::Config, envelope::Envelope, events::Relation, log, message::Message,
+    metrics::Metrics, sink::Sink, time,
+};
 use std::{
-    io::{Error, ErrorKind, Write},
-    process::{Command, Stdio},
+    cell::Cell,
+    fmt::Debug,
+    io::{Error, ErrorKind},
+    time::Instant,
 };
 
+/// A matrix backend
+pub trait Backend: Debug {
+    /// Checks if the backend can deliver a message as requested
+    ///
+    /// # Note
+    /// Messages that the backend cannot deliver are rejected with [`ErrorKind::Unsupported`] before anything is sent
+    fn check(&self, _envelope: &Envelope) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Sends a message with an optional relation to a previously sent event and returns the event ID if the backend can
+    /// provide it
+    fn send(&self, envelope: &Envelope, relation: Option<&Relation>) -> Result<Option<String>, Error>;
+
+    /// Reacts to a previously sent event
+    fn react(&self, event_id: &str, reaction: &str) -> Result<(), Error>;
+
+    /// Redacts a previously sent event
+    fn redact(&self, event_id: &str, reason: &str) -> Result<(), Error>;
+}
+
 /// The matrix adapter
+#[derive(Debug)]
 pub struct Matrix<'a> {
-    /// The config
-    config: &'a Config,
+    /// The configured backend
+    backend: Box<dyn Backend + 'a>,
+    /// The metrics
+    metrics: &'a Metrics,
+    /// The UNIX timestamp of the last successful send if any
+    last_send: Cell<Option<u64>>,
 }
 impl<'a> Matrix<'a> {
-    /// Creates a new matrix adapter
-    pub fn new(config: &'a Config) -> Result<Self, Error> {
-        // Init self and get username to ensure matrix commander exists and is configured
-        let this = Self { config };
-        let username = this.matrix_commander(&["--whoami"], b"")?;
+    /// Creates a new matrix adapter with the configured backend
+    pub fn new(config: &'a Config, metrics: &'a Metrics) -> Result<Self, Error> {
+        let backend: Box<dyn Backend + 'a> = match config.BACKEND.as_str() {
+            "matrix-commander" => Box::new(Commander::new(config, metrics)?),
+            "sink" => Box::new(Sink::new(config)?),
+            backend => return Err(Error::new(ErrorKind::InvalidInput, format!("unknown backend: {backend}"))),
+        };
+        Ok(Self { backend, metrics, last_send: Cell::new(None) })
+    }
 
-        // Print status and return instance
-        eprintln!("*> User: `{}`", username.trim_end());
-        Ok(this)
+    /// Checks if the backend can deliver a message as requested
+    pub fn check(&self, envelope: &Envelope) -> Result<(), Error> {
+        self.backend.check(envelope)
     }
 
-    /// Sends a message to the matrix-commander's configured default room
-    pub fn send(&self, message: Message) -> Result<(), Error> {
-        // Prepare message
-        let (args, data) = match message {
-            Message::Plaintext { text } => (vec!["--message", "-"], text),
-            Message::Markdown { markdown } => (vec!["--message", "-", "--markdown"], markdown),
-            Message::Raw { ref name, contents } => (vec!["--file", "-", "--file-name", name], contents),
+    /// Sends a message and returns the event ID if the backend can provide it
+    pub fn send(&self, envelope: &Envelope) -> Result<Option<String>, Error> {
+        self.send_related(envelope, None)
+    }
+
+    /// Sends a message with a relation to a previously sent event and returns the event ID if the backend can provide it
+    pub fn send_related(&self, envelope: &Envelope, relation: Option<&Relation>) -> Result<Option<String>, Error> {
+        // Send the message and count the result
+        let (kind, start) = (envelope.message.kind(), Instant::now());
+        let event_id = match self.backend.send(envelope, relation) {
+            Ok(event_id) => event_id,
+            Err(e) => {
+                log::warning!("Failed to send {kind} message: {e}");
+                self.metrics.failed(kind);
+                return Err(e);
+            }
         };
 
-        // Send message
-        self.matrix_commander(&args, &data)?;
-        Ok(())
+        // Record the successful send
+        let uploaded = match &envelope.message {
+            Message::Raw { contents, .. } => contents.len,
+            _ => 0,
+        };
+        self.metrics.sent(kind, start.elapsed(), uploaded);
+        self.last_send.set(Some(time::now()));
+        match &event_id {
+            Some(event_id) => log::info!("Sent {kind} message as {event_id}"),
+            None => log::info!("Sent {kind} message"),
+        }
+        Ok(event_id)
     }
 
-    /// Executes a matrix commander command
-    fn matrix_commander(&self, args: &[&str], data: &[u8]) -> Result<String, Error> {
-        // Start the matrix commander
-        let mut matrix_commander = Command::new(&self.config.MATRIX_PATH)
-            .args(args)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::inherit())
-            .spawn()?;
-
-        // Write all data to stdin and close it
-        // Note: Since we create a dedicated pipe for stdin, this should never fail
-        #[allow(clippy::expect_used)]
-        let mut stdin = matrix_commander.stdin.take().expect("failed to get stdin from spawned child process");
-        stdin.write_all(data)?;
-        drop(stdin);
+    /// The UNIX timestamp of the last successful send if any
+    pub fn last_send(&self) -> Option<u64> {
+        self.last_send.get()
+    }
 
-        // Wait for matrix commander to complete
-        let result = matrix_commander.wait_with_output()?;
-        let true = result.status.success() else {
-            // Signalize that matrix commander failed
-            return Err(Error::from(ErrorKind::Other));
-        };
+    /// Reacts to a previously sent event
+    pub fn react(&self, event_id: &str, reaction: &str) -> Result<(), Error> {
+        self.backend.react(event_id, reaction)
+    }
 
-        // Return stdout
-        match String::from_utf8(result.stdout) {
-            Ok(stdout) => Ok(stdout),
-            Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
-        }
+    /// Redacts a previously sent event
+    pub fn redact(&self, event_id: &str, reason: &str) -> Result<(), Error> {
+        self.backend.redact(event_id, reason)
     }
 }
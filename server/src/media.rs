@@ -0,0 +1,165 @@
+//! Content type detection for attachments
+
+/// The amount of leading bytes that are needed to detect the content type
+pub const SNIFF_SIZE: u64 = 32;
+
+/// The known magic bytes as `(offset, magic, MIME type)`
+const MAGIC: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"ID3", "audio/mpeg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"\x1a\x45\xdf\xa3", "video/webm"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"\x28\xb5\x2f\xfd", "application/zstd"),
+];
+/// The known form types of RIFF containers, which start with `RIFF`, as `(form type, MIME type)`
+const RIFF_FORMS: &[(&[u8], &str)] = &[(b"WEBP", "image/webp"), (b"WAVE", "audio/wav")];
+/// The known sizes of the DIB header that follows the file header of BMP images
+const BMP_DIB_SIZES: &[u32] = &[12, 40, 108, 124];
+/// The known major brands of ISO base media files, which start with an `ftyp` box, as `(brand, MIME type)`
+const BRANDS: &[(&[u8], &str)] = &[
+    (b"isom", "video/mp4"),
+    (b"iso2", "video/mp4"),
+    (b"mp41", "video/mp4"),
+    (b"mp42", "video/mp4"),
+    (b"avc1", "video/mp4"),
+    (b"dash", "video/mp4"),
+    (b"M4V ", "video/mp4"),
+    (b"M4A ", "audio/mp4"),
+    (b"M4B ", "audio/mp4"),
+    (b"qt  ", "video/quicktime"),
+    (b"heic", "image/heic"),
+    (b"heix", "image/heic"),
+    (b"mif1", "image/heif"),
+    (b"avif", "image/avif"),
+];
+/// The known file extensions as `(extension, MIME type)`
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("mov", "video/quicktime"),
+    ("heic", "image/heic"),
+    ("avif", "image/avif"),
+    ("webm", "video/webm"),
+    ("mkv", "video/x-matroska"),
+    ("txt", "text/plain"),
+    ("log", "text/plain"),
+    ("csv", "text/csv"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("html", "text/html"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("zst", "application/zstd"),
+    ("tar", "application/x-tar"),
+];
+/// Detects the MIME type of an attachment from its magic bytes or, as fallback, from its file extension
+///
+/// # Note
+/// ISO base media files and RIFF containers are detected by their major brand or form type respectively; files with an
+/// unknown brand or form type fall back to their extension
+pub fn sniff(name: &str, contents: &[u8]) -> &'static str {
+    if contents.get(4..8) == Some(b"ftyp") {
+        // Match the major brand of ISO base media files
+        let brand = contents.get(8..12);
+        if let Some((_, mimetype)) = BRANDS.iter().find(|(known, _)| brand == Some(*known)) {
+            return mimetype;
+        }
+    } else if contents.get(..4) == Some(b"RIFF") {
+        // Match the form type of RIFF containers
+        let form = contents.get(8..12);
+        if let Some((_, mimetype)) = RIFF_FORMS.iter().find(|(known, _)| form == Some(*known)) {
+            return mimetype;
+        }
+    } else if is_bmp(contents) {
+        // Match the BMP headers
+        return "image/bmp";
+    } else {
+        // Match the magic bytes
+        for (offset, magic, mimetype) in MAGIC {
+            let end = offset.saturating_add(magic.len());
+            if contents.get(*offset..end) == Some(magic) {
+                return mimetype;
+            }
+        }
+    }
+
+    // Match the file extension
+    let extension = name.rsplit_once('.').map(|(_, extension)| extension).unwrap_or_default();
+    match EXTENSIONS.iter().find(|(known, _)| known.eq_ignore_ascii_case(extension)) {
+        Some((_, mimetype)) => mimetype,
+        None => "application/octet-stream",
+    }
+}
+
+/// Checks if the leading bytes are a BMP file header that is followed by a DIB header
+///
+/// # Note
+/// The magic bytes `BM` alone would match any text that starts with them, so the reserved fields must be zero, the DIB
+/// header must have a known size and the file size must cover both headers
+fn is_bmp(contents: &[u8]) -> bool {
+    // Read the header fields
+    let field = |offset: usize| {
+        let bytes = contents.get(offset..offset.saturating_add(4))?;
+        <[u8; 4]>::try_from(bytes).ok().map(u32::from_le_bytes)
+    };
+    let (Some(b"BM"), Some(size), Some(0), Some(dib_size)) = (contents.get(..2), field(2), field(6), field(14)) else {
+        return false;
+    };
+
+    // Validate the header sizes
+    BMP_DIB_SIZES.contains(&dib_size) && size >= dib_size.saturating_add(14)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sniff;
+
+    #[test]
+    fn iso_media_brands() {
+        // ISO base media files are detected by their major brand
+        let file = |brand: &[u8]| [b"\0\0\0\x20ftyp".as_slice(), brand, b"\0\0\0\0"].concat();
+        assert_eq!(sniff("video.bin", &file(b"isom")), "video/mp4");
+        assert_eq!(sniff("audio.bin", &file(b"M4A ")), "audio/mp4");
+        assert_eq!(sniff("video.bin", &file(b"qt  ")), "video/quicktime");
+        assert_eq!(sniff("image.bin", &file(b"heic")), "image/heic");
+        assert_eq!(sniff("image.bin", &file(b"avif")), "image/avif");
+
+        // Unknown brands fall back to the extension
+        assert_eq!(sniff("audio.m4a", &file(b"xxxx")), "audio/mp4");
+        assert_eq!(sniff("image.heic", &file(b"xxxx")), "image/heic");
+        assert_eq!(sniff("unknown.bin", &file(b"xxxx")), "application/octet-stream");
+    }
+
+    #[test]
+    fn container_and_header_checks() {
+        // RIFF containers are detected by their form type, but only with the container tag
+        assert_eq!(sniff("image.bin", b"RIFF\x24\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff("audio.bin", b"RIFF\x24\0\0\0WAVEfmt "), "audio/wav");
+        assert_eq!(sniff("notes.txt", b"xxxxxxxxWEBPxxxx"), "text/plain");
+        assert_eq!(sniff("notes.txt", b"RIFF\x24\0\0\0xxxxxxxx"), "text/plain");
+
+        // BMP images are detected by their headers, so texts that start with `BM` stay text
+        let bmp = [b"BM\x46\0\0\0\0\0\0\0\x36\0\0\0".as_slice(), &40u32.to_le_bytes(), &[0; 14]].concat();
+        assert_eq!(sniff("image.bin", &bmp), "image/bmp");
+        assert_eq!(sniff("notes.txt", b"BMW service is due next week"), "text/plain");
+        assert_eq!(sniff("notes.txt", b"BM"), "text/plain");
+    }
+}
@@ -0,0 +1,130 @@
+//! User mentions and room pings
+
+use crate::{config::Config, envelope::Metadata, html, log, message::Message};
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+};
+
+/// Resolves mentions and formats them into messages
+#[derive(Debug)]
+pub struct Mentions {
+    /// The aliases that map a name to a list of user IDs
+    aliases: BTreeMap<String, Vec<String>>,
+}
+impl Mentions {
+    /// The maximum length of a user ID
+    const USER_ID_MAX: usize = 255;
+
+    /// Creates a new mention resolver
+    ///
+    /// # Format
+    /// The aliases are configured as `;`-separated list of `name=user-id,user-id,...` entries, e.g.
+    /// `oncall=@alice:example.org,@bob:example.org;dba=@carol:example.org`
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        let mut aliases = BTreeMap::new();
+        for alias in config.MENTION_ALIASES.split(';').filter(|alias| !alias.trim().is_empty()) {
+            // Parse the alias
+            let Some((name, user_ids)) = alias.split_once('=') else {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid mention alias: {alias}")));
+            };
+
+            // Validate the user IDs and register the alias
+            let user_ids: Vec<String> = user_ids.split(',').map(|user_id| user_id.trim().to_string()).collect();
+            if let Some(invalid) = user_ids.iter().find(|user_id| !Self::is_user_id(user_id)) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid user ID in mention alias: {invalid}")));
+            }
+            aliases.insert(name.trim().to_string(), user_ids);
+        }
+        Ok(Self { aliases })
+    }
+
+    /// Resolves the mentioned user IDs and aliases into a deduplicated list of valid user IDs
+    ///
+    /// # Note
+    /// Invalid user IDs and unknown aliases are ignored
+    pub fn resolve(&self, mentions: &[String]) -> Vec<String> {
+        let mut resolved = Vec::new();
+        for mention in mentions {
+            // Resolve the mention
+            let user_ids = match self.aliases.get(mention) {
+                Some(user_ids) => user_ids.as_slice(),
+                None if Self::is_user_id(mention) => std::slice::from_ref(mention),
+                None => {
+                    log::warning!("Invalid mention: {mention}");
+                    continue;
+                }
+            };
+
+            // Register the user IDs
+            for user_id in user_ids {
+                if !resolved.contains(user_id) {
+                    resolved.push(user_id.clone());
+                }
+            }
+        }
+        resolved
+    }
+
+    /// Prepends the room ping and the mentioned users to a message
+    ///
+    /// # Important
+    /// The mentions in the metadata must be resolved via [`Self::resolve`] before
+    pub fn format(metadata: &Metadata, message: &mut Message) {
+        // Collect the mentions
+        let room = metadata.ping_room.then_some("@room");
+        let mentions: Vec<&str> = room.into_iter().chain(metadata.mentions.iter().map(String::as_str)).collect();
+        if mentions.is_empty() {
+            // Nothing to format
+            return;
+        }
+
+        // Prepend the mentions
+        let plaintext = format!("{} ", mentions.join(" "));
+        match message {
+            Message::Plaintext { text } | Message::Notice { text } | Message::Emote { text } => {
+                text.splice(0..0, plaintext.bytes());
+            }
+            Message::Markdown { markdown } => {
+                let links = mentions.iter().map(|mention| match *mention {
+                    "@room" => String::from("@room"),
+                    user_id => format!("[{}](https://matrix.to/#/{user_id})", user_id.replace('_', "\\_")),
+                });
+                let links = format!("{} ", links.collect::<Vec<_>>().join(" "));
+                markdown.splice(0..0, links.bytes());
+            }
+            Message::Html { html: body, fallback } => {
+                let pills = mentions.iter().map(|mention| match *mention {
+                    "@room" => String::from("@room"),
+                    user_id => format!(r#"<a href="https://matrix.to/#/{0}">{0}</a>"#, html::escape(user_id)),
+                });
+                let pills = format!("{} ", pills.collect::<Vec<_>>().join(" "));
+                body.splice(0..0, pills.bytes());
+                fallback.splice(0..0, plaintext.bytes());
+            }
+            Message::Reaction { .. } | Message::Redaction { .. } | Message::Raw { .. } => (/* cannot mention users */),
+        }
+    }
+
+    /// Checks if a string is a valid matrix user ID, i.e. `@localpart:server.name`
+    ///
+    /// # Note
+    /// Only the non-historical localpart characters are accepted, so that user IDs can be embedded into markup safely
+    pub fn is_user_id(user_id: &str) -> bool {
+        // Split the user ID
+        let Some((localpart, server_name)) = user_id.strip_prefix('@').and_then(|user_id| user_id.split_once(':'))
+        else {
+            return false;
+        };
+
+        // Validate the fields
+        let is_localpart = |char_: char| matches!(char_, 'a'..='z' | '0'..='9' | '.' | '_' | '=' | '-' | '/' | '+');
+        let is_server_name =
+            |char_: char| char_.is_ascii_alphanumeric() || matches!(char_, '.' | '-' | ':' | '[' | ']');
+        user_id.len() <= Self::USER_ID_MAX
+            && !localpart.is_empty()
+            && localpart.chars().all(is_localpart)
+            && !server_name.is_empty()
+            && server_name.chars().all(is_server_name)
+    }
+}
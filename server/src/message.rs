@@ -1,5 +1,37 @@
 //! A message
 
+use std::{
+    fs::File,
+    io::{Error, Read, Seek, SeekFrom, Take},
+    path::PathBuf,
+};
+
+/// An attachment, i.e. a region of a file that is streamed to the backend instead of being buffered in memory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    /// The path to the file that contains the attachment
+    pub path: PathBuf,
+    /// The offset of the attachment within the file
+    pub offset: u64,
+    /// The attachment length
+    pub len: u64,
+}
+impl Attachment {
+    /// Opens the attachment for reading
+    pub fn open(&self) -> Result<Take<File>, Error> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        Ok(file.take(self.len))
+    }
+
+    /// Reads the first `limit` bytes of the attachment, e.g. to detect the content type
+    pub fn head(&self, limit: u64) -> Result<Vec<u8>, Error> {
+        let mut head = Vec::new();
+        self.open()?.take(limit).read_to_end(&mut head)?;
+        Ok(head)
+    }
+}
+
 /// A message
 #[derive(Debug, Clone)]
 pub enum Message {
@@ -13,11 +45,90 @@ pub enum Message {
         /// The markdown message to send
         markdown: Vec<u8>,
     },
+    /// A bot-style notice which is usually rendered muted and does not trigger other bots
+    Notice {
+        /// The plaintext notice to send
+        text: Vec<u8>,
+    },
+    /// An emote, i.e. an action like `/me`
+    Emote {
+        /// The plaintext emote to send
+        text: Vec<u8>,
+    },
+    /// A pre-rendered HTML message
+    Html {
+        /// The HTML body to send
+        html: Vec<u8>,
+        /// The plaintext fallback for clients that cannot render HTML
+        fallback: Vec<u8>,
+    },
+    /// A reaction to a previously sent message
+    Reaction {
+        /// The event key of the message to react to
+        target: String,
+        /// The reaction, usually an emoji
+        reaction: Vec<u8>,
+    },
+    /// A redaction of a previously sent message
+    Redaction {
+        /// The event key of the message to redact
+        target: String,
+        /// The optional reason for the redaction
+        reason: Vec<u8>,
+    },
     /// The raw file to send
     Raw {
         /// The name of the file (without the .raw-extension)
         name: String,
         /// The file contents
-        contents: Vec<u8>,
+        contents: Attachment,
     },
 }
+impl Message {
+    /// The message type as used in the IPC envelope
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Plaintext { .. } => "plaintext",
+            Self::Markdown { .. } => "markdown",
+            Self::Notice { .. } => "notice",
+            Self::Emote { .. } => "emote",
+            Self::Html { .. } => "html",
+            Self::Reaction { .. } => "reaction",
+            Self::Redaction { .. } => "redaction",
+            Self::Raw { .. } => "raw",
+        }
+    }
+
+    /// The message payload, i.e. the message body
+    ///
+    /// # Note
+    /// Attachments are streamed from their file and have no in-memory payload
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            Self::Plaintext { text } | Self::Notice { text } | Self::Emote { text } => text,
+            Self::Markdown { markdown } => markdown,
+            Self::Html { html, .. } => html,
+            Self::Reaction { reaction, .. } => reaction,
+            Self::Redaction { reason, .. } => reason,
+            Self::Raw { .. } => &[],
+        }
+    }
+
+    /// The human-readable text of a text message, i.e. the body or the plaintext fallback for HTML messages
+    pub fn text(&self) -> Option<&[u8]> {
+        match self {
+            Self::Plaintext { text } | Self::Notice { text } | Self::Emote { text } => Some(text),
+            Self::Markdown { markdown } => Some(markdown),
+            Self::Html { fallback, .. } => Some(fallback),
+            Self::Reaction { .. } | Self::Redaction { .. } | Self::Raw { .. } => None,
+        }
+    }
+
+    /// The event key of the previously sent message that is targeted by a reaction or redaction
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            Self::Reaction { target, .. } | Self::Redaction { target, .. } => Some(target),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Metrics in the Prometheus text format for the node exporter's textfile collector
+
+use crate::config::Config;
+use std::{cell::RefCell, collections::BTreeMap, fs, io::Error, path::PathBuf, time::Duration};
+
+/// The room label; `matrix-commander-rs` always sends into its configured default room
+const ROOM: &str = "default";
+/// The upper bounds of the send latency histogram buckets in seconds
+const LATENCY_BUCKETS: [f64; 9] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// A latency histogram
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// The amount of observations per bucket; the observations are not cumulated
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    /// The sum of all observations in seconds
+    sum: f64,
+    /// The amount of all observations
+    count: u64,
+}
+
+/// The collected metrics
+#[derive(Debug, Clone, Default)]
+struct Registry {
+    /// The received messages by type
+    received: BTreeMap<&'static str, u64>,
+    /// The sent messages by type
+    sent: BTreeMap<&'static str, u64>,
+    /// The messages that failed to send by type
+    failed: BTreeMap<&'static str, u64>,
+    /// The messages that are retried after a failure by type
+    retried: BTreeMap<&'static str, u64>,
+    /// The messages that have been moved to a dead-letter directory by directory
+    archived: BTreeMap<&'static str, u64>,
+    /// The `matrix-commander-rs` exit codes; `signal` if it has been terminated by a signal
+    exits: BTreeMap<String, u64>,
+    /// The uploaded attachment bytes
+    uploaded: u64,
+    /// The amount of pending messages
+    queue: usize,
+    /// The send latency
+    latency: Histogram,
+}
+
+/// Collects metrics and writes them to a textfile
+///
+/// # Note
+/// The metrics are kept in memory only, so they are reset on restart
+#[derive(Debug)]
+pub struct Metrics {
+    /// The path to the metrics file if any
+    path: Option<PathBuf>,
+    /// The collected metrics
+    registry: RefCell<Registry>,
+}
+impl Metrics {
+    /// Creates a new metrics collector
+    pub fn new(config: &Config) -> Self {
+        let path = Some(PathBuf::from(&config.METRICS_PATH)).filter(|path| !path.as_os_str().is_empty());
+        Self { path, registry: RefCell::default() }
+    }
+
+    /// Counts a received message
+    pub fn received(&self, kind: &'static str) {
+        let mut registry = self.registry.borrow_mut();
+        Self::increment(&mut registry.received, kind, 1);
+    }
+
+    /// Counts a sent message together with its send latency and its attachment size
+    pub fn sent(&self, kind: &'static str, latency: Duration, uploaded: u64) {
+        let mut registry = self.registry.borrow_mut();
+        Self::increment(&mut registry.sent, kind, 1);
+        registry.uploaded = registry.uploaded.saturating_add(uploaded);
+
+        // Observe the latency
+        let latency = latency.as_secs_f64();
+        let histogram = &mut registry.latency;
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| latency <= *bound) {
+            histogram.buckets.get_mut(bucket).into_iter().for_each(|count| *count = count.saturating_add(1));
+        }
+        histogram.sum += latency;
+        histogram.count = histogram.count.saturating_add(1);
+    }
+
+    /// Counts a message that failed to send
+    pub fn failed(&self, kind: &'static str) {
+        let mut registry = self.registry.borrow_mut();
+        Self::increment(&mut registry.failed, kind, 1);
+    }
+
+    /// Counts a message that is retried after it has failed
+    pub fn retried(&self, kind: &'static str) {
+        let mut registry = self.registry.borrow_mut();
+        Self::increment(&mut registry.retried, kind, 1);
+    }
+
+    /// Counts a message that has been moved to the given dead-letter directory
+    pub fn archived(&self, subdir: &'static str) {
+        let mut registry = self.registry.borrow_mut();
+        Self::increment(&mut registry.archived, subdir, 1);
+    }
+
+    /// Counts an exit code of `matrix-commander-rs`; `None` if it has been terminated by a signal
+    pub fn commander_exit(&self, code: Option<i32>) {
+        let code = code.map(|code| code.to_string()).unwrap_or_else(|| String::from("signal"));
+        let mut registry = self.registry.borrow_mut();
+        Self::increment(&mut registry.exits, code, 1);
+    }
+
+    /// Sets the queue depth and writes the metrics file if configured
+    pub fn report(&self, queue: usize) -> Result<(), Error> {
+        // Update the queue depth
+        self.registry.borrow_mut().queue = queue;
+        let Some(path) = &self.path else {
+            // Nothing to write
+            return Ok(());
+        };
+
+        // Atomically replace the metrics file; the textfile collector ignores files without `.prom` extension
+        let tmp = path.with_extension("prom.tmp");
+        fs::write(&tmp, self.encode())?;
+        fs::rename(tmp, path)
+    }
+
+    /// Encodes the metrics in the Prometheus text format
+    fn encode(&self) -> String {
+        let registry = self.registry.borrow();
+        let mut metrics = String::new();
+
+        // Encode the message counters
+        let counters = [
+            ("received", "The received IPC messages by type", &registry.received, String::new()),
+            ("sent", "The sent messages by type and room", &registry.sent, format!(r#",room="{ROOM}""#)),
+            (
+                "failed",
+                "The messages that failed to send by type and room",
+                &registry.failed,
+                format!(r#",room="{ROOM}""#),
+            ),
+            ("retried", "The messages that are retried after a failure by type", &registry.retried, String::new()),
+        ];
+        for (name, help, values, labels) in counters {
+            let name = format!("sendmatrix_messages_{name}_total");
+            Self::family(&mut metrics, &name, "counter", help);
+            for (kind, count) in values {
+                metrics.push_str(&format!("{name}{{type=\"{kind}\"{labels}}} {count}\n"));
+            }
+        }
+
+        // Encode the dead-letters, exit codes and uploaded bytes
+        let name = "sendmatrix_messages_archived_total";
+        Self::family(&mut metrics, name, "counter", "The messages that have been moved to a dead-letter directory");
+        for (subdir, count) in &registry.archived {
+            metrics.push_str(&format!("{name}{{directory=\"{subdir}\"}} {count}\n"));
+        }
+        let name = "sendmatrix_commander_exits_total";
+        Self::family(&mut metrics, name, "counter", "The exit codes of matrix-commander-rs");
+        for (code, count) in &registry.exits {
+            metrics.push_str(&format!("{name}{{code=\"{code}\"}} {count}\n"));
+        }
+        let name = "sendmatrix_uploaded_bytes_total";
+        Self::family(&mut metrics, name, "counter", "The uploaded attachment bytes");
+        metrics.push_str(&format!("{name} {}\n", registry.uploaded));
+
+        // Encode the queue depth
+        let name = "sendmatrix_queue_depth";
+        Self::family(&mut metrics, name, "gauge", "The amount of pending IPC messages");
+        metrics.push_str(&format!("{name} {}\n", registry.queue));
+
+        // Encode the latency histogram with cumulative buckets
+        let Histogram { buckets, sum, count } = &registry.latency;
+        let name = "sendmatrix_send_duration_seconds";
+        Self::family(&mut metrics, name, "histogram", "The send latency in seconds");
+        let mut cumulative = 0u64;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(buckets) {
+            cumulative = cumulative.saturating_add(*bucket);
+            metrics.push_str(&format!("{name}_bucket{{le=\"{bound}\"}} {cumulative}\n"));
+        }
+        metrics.push_str(&format!("{name}_bucket{{le=\"+Inf\"}} {count}\n"));
+        metrics.push_str(&format!("{name}_sum {sum}\n"));
+        metrics.push_str(&format!("{name}_count {count}\n"));
+        metrics
+    }
+
+    /// Appends the help and type lines of a metric family
+    fn family(metrics: &mut String, name: &str, type_: &str, help: &str) {
+        metrics.push_str(&format!("# HELP {name} {help}\n"));
+        metrics.push_str(&format!("# TYPE {name} {type_}\n"));
+    }
+
+    /// Increments a labeled counter
+    fn increment<K>(counters: &mut BTreeMap<K, u64>, label: K, amount: u64)
+    where
+        K: Ord,
+    {
+        let counter = counters.entry(label).or_default();
+        *counter = counter.saturating_add(amount);
+    }
+}
@@ -0,0 +1,87 @@
+//! Message priorities and their formatting
+
+use crate::{config::Config, html, message::Message};
+use std::{
+    io::{Error, ErrorKind},
+    str::FromStr,
+};
+
+/// A message priority
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// A low priority message; sent as notice so it doesn't trigger notifications
+    Low,
+    /// A normal priority message
+    #[default]
+    Normal,
+    /// A high priority message
+    High,
+    /// A critical message; can ping the entire room
+    Critical,
+}
+impl Priority {
+    /// Applies the priority-dependent prefix to a message
+    pub fn format(self, message: &mut Message, config: &Config) {
+        // Get the prefix
+        let prefix = match self {
+            Self::Low => &config.PRIORITY_PREFIX_LOW,
+            Self::Normal => &config.PRIORITY_PREFIX_NORMAL,
+            Self::High => &config.PRIORITY_PREFIX_HIGH,
+            Self::Critical => &config.PRIORITY_PREFIX_CRITICAL,
+        };
+        if prefix.is_empty() {
+            // Nothing to format
+            return;
+        }
+
+        // Prepend the prefix to the text and, for HTML messages, to the plaintext fallback
+        let prefix = format!("{prefix} ");
+        match message {
+            Message::Plaintext { text } | Message::Notice { text } | Message::Emote { text } => {
+                text.splice(0..0, prefix.bytes());
+            }
+            Message::Markdown { markdown } => {
+                markdown.splice(0..0, prefix.bytes());
+            }
+            Message::Html { html: body, fallback } => {
+                body.splice(0..0, html::escape(&prefix).bytes());
+                fallback.splice(0..0, prefix.bytes());
+            }
+            Message::Raw { .. } => (/* attachments are not formatted */),
+            Message::Reaction { .. } | Message::Redaction { .. } => (/* reactions and redactions are not formatted */),
+        }
+    }
+
+    /// The priority name as used in the IPC envelope
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+            Self::Critical => "critical",
+        }
+    }
+
+    /// Whether the message should ping the entire room
+    pub fn is_ping(self, config: &Config) -> bool {
+        self == Self::Critical && config.PRIORITY_PING_CRITICAL
+    }
+
+    /// Whether the message should be sent as notice
+    pub fn is_notice(self, config: &Config) -> bool {
+        self == Self::Low && config.PRIORITY_NOTICE_LOW
+    }
+}
+impl FromStr for Priority {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            "critical" => Ok(Self::Critical),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("invalid priority: {s}"))),
+        }
+    }
+}
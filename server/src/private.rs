@@ -0,0 +1,98 @@
+//! Server-only subdirectories and files within the IPC directory
+
+use crate::{audit, config::Config};
+use std::{
+    fs::{self, File},
+    io::{Error, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+/// Creates a server-only subdirectory of the IPC directory or validates an existing one and returns its path
+///
+/// # Note
+/// Producers can write to the IPC directory, so an existing subdirectory must be a real directory that is owned by the
+/// server. It is restricted to the mode `0700`, so that producers can neither read nor plant files within it.
+#[cfg(unix)]
+pub fn dir(config: &Config, subdir: &str) -> Result<PathBuf, Error> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    // Create the subdirectory if it does not exist
+    let path = Path::new(&config.IPC_PATH);
+    let dir = path.join(subdir);
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e),
+        _ => (/* subdirectory exists */),
+    }
+
+    // Ensure that the subdirectory is a real directory that is owned by the server, i.e. by the owner of the probe file
+    let metadata = fs::symlink_metadata(&dir)?;
+    if metadata.is_symlink() || !metadata.is_dir() {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("not a directory: {}", dir.display())));
+    }
+    let server = audit::probe(path)?;
+    if metadata.uid() != server.uid() {
+        return Err(Error::new(ErrorKind::PermissionDenied, format!("not owned by the server: {}", dir.display())));
+    }
+
+    // Restrict the subdirectory to the server
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    Ok(dir)
+}
+/// Creates a server-only subdirectory of the IPC directory or validates an existing one and returns its path
+///
+/// # Note
+/// File modes and owners are only supported on unix, so the subdirectory gets the default permissions on other
+/// platforms
+#[cfg(not(unix))]
+pub fn dir(config: &Config, subdir: &str) -> Result<PathBuf, Error> {
+    let dir = Path::new(&config.IPC_PATH).join(subdir);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Removes all files within a server-only subdirectory
+pub fn clear(dir: &Path) -> Result<(), Error> {
+    for maybe_entry in fs::read_dir(dir)? {
+        match fs::remove_file(maybe_entry?.path()) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => (/* file has been removed */),
+        }
+    }
+    Ok(())
+}
+
+/// Atomically replaces a file within a server-only subdirectory
+///
+/// # Note
+/// The contents are written to a new temporary file via [`create`], which is then renamed over the file
+pub fn replace(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    // Remove a stale temporary file
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => (/* temporary file has been removed */),
+    }
+
+    // Write the temporary file and replace the file
+    create(Path::new(&tmp))?.write_all(contents)?;
+    fs::rename(tmp, path)
+}
+
+/// Creates a new file that is only readable by the server
+///
+/// # Note
+/// The file is created exclusively and symlinks are not followed, so that an existing file is never written to
+#[cfg(unix)]
+pub fn create(path: &Path) -> Result<File, Error> {
+    use std::os::unix::fs::OpenOptionsExt;
+    File::options().write(true).create_new(true).custom_flags(libc::O_NOFOLLOW).mode(0o600).open(path)
+}
+/// Creates a new file that is only readable by the server
+///
+/// # Note
+/// File modes are only supported on unix, so the file gets the default permissions on other platforms
+#[cfg(not(unix))]
+pub fn create(path: &Path) -> Result<File, Error> {
+    File::options().write(true).create_new(true).open(path)
+}
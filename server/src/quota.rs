@@ -0,0 +1,75 @@
+//! Hourly per-owner quotas on the amount and size of messages
+
+use crate::{config::Config, envelope::Envelope};
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+};
+
+/// The usage of an owner within the current hour
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    /// The hour since the UNIX epoch this usage refers to
+    hour: u64,
+    /// The amount of messages
+    messages: u64,
+    /// The amount of message bytes
+    bytes: u64,
+}
+
+/// Enforces hourly quotas per owner UID
+#[derive(Debug)]
+pub struct Quotas {
+    /// The maximum amount of messages per owner and hour; `0` disables the limit
+    messages_max: u64,
+    /// The maximum amount of message bytes per owner and hour; `0` disables the limit
+    bytes_max: u64,
+    /// The usage by owner UID
+    usage: BTreeMap<u32, Usage>,
+}
+impl Quotas {
+    /// The length of a quota window in seconds
+    const WINDOW: u64 = 60 * 60;
+
+    /// Creates a new quota tracker
+    pub fn new(config: &Config) -> Self {
+        Self {
+            messages_max: config.QUOTA_MESSAGES_HOURLY,
+            bytes_max: config.QUOTA_BYTES_HOURLY,
+            usage: BTreeMap::new(),
+        }
+    }
+
+    /// Accounts a message to its owner and checks if the owner has exceeded a quota
+    ///
+    /// # Note
+    /// Messages without owner are not limited. If a quota is exceeded, an error of kind [`ErrorKind::QuotaExceeded`]
+    /// is returned and the message is not accounted.
+    pub fn check(&mut self, envelope: &Envelope, now: u64) -> Result<(), Error> {
+        // Get the usage of the owner within the current hour
+        let (Some(owner), true) = (envelope.metadata.owner, self.messages_max > 0 || self.bytes_max > 0) else {
+            return Ok(());
+        };
+        let hour = now / Self::WINDOW;
+        let usage = self.usage.entry(owner).or_default();
+        if usage.hour != hour {
+            *usage = Usage { hour, ..Usage::default() };
+        }
+
+        // Check the quotas
+        let messages = usage.messages.saturating_add(1);
+        let bytes = usage.bytes.saturating_add(envelope.size());
+        if self.messages_max > 0 && messages > self.messages_max {
+            let message = format!("owner UID {owner} exceeded the hourly quota of {} messages", self.messages_max);
+            return Err(Error::new(ErrorKind::QuotaExceeded, message));
+        }
+        if self.bytes_max > 0 && bytes > self.bytes_max {
+            let message = format!("owner UID {owner} exceeded the hourly quota of {} bytes", self.bytes_max);
+            return Err(Error::new(ErrorKind::QuotaExceeded, message));
+        }
+
+        // Account the message
+        *usage = Usage { hour, messages, bytes };
+        Ok(())
+    }
+}
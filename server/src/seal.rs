@@ -0,0 +1,204 @@
+//! Decryption of sealed IPC messages
+//!
+//! # Format
+//! A sealed message consists of the magic bytes [`MAGIC`], the 32 byte ephemeral X25519 public key of the producer and
+//! the encrypted envelope. The envelope is split into chunks of [`CHUNK_SIZE`] bytes, which are encrypted with
+//! ChaCha20-Poly1305 individually; the last chunk may be shorter. The key is derived from the X25519 shared secret via
+//! HKDF-SHA256 with the ephemeral and the server public key as salt. The nonce of a chunk is its 88 bit big-endian
+//! index, followed by `0x01` for the last chunk or `0x00` for all other chunks, so that chunks cannot be reordered or
+//! truncated.
+
+use crate::{config::Config, log, private};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use hkdf::Hkdf;
+use sendmatrix_common::hex::decode_hex;
+use sha2::Sha256;
+use std::{
+    fmt::{self, Debug, Formatter},
+    fs::{self, File},
+    io::{Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// The magic bytes of a sealed message
+const MAGIC: &[u8; 8] = b"SMSEAL01";
+/// The size of a plaintext chunk
+const CHUNK_SIZE: usize = 64 * 1024;
+/// The size of the authentication tag of a chunk
+const TAG_SIZE: usize = 16;
+/// The HKDF info to derive the message key
+const INFO: &[u8] = b"sendmatrix sealed message";
+
+/// A private key which is redacted in debug output
+struct Key(StaticSecret);
+impl Debug for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Key(<redacted>)")
+    }
+}
+
+/// Decrypts sealed IPC messages into a private spool directory
+#[derive(Debug)]
+pub struct Unsealer {
+    /// The private key of the server if sealed messages are accepted
+    key: Option<Key>,
+    /// The directory for the decrypted messages
+    spool: PathBuf,
+}
+impl Unsealer {
+    /// The subdirectory of the IPC directory where decrypted messages are stored while they are processed
+    pub const SUBDIR: &'static str = "unsealed";
+
+    /// Creates a new unsealer and removes stale decrypted messages
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        // Parse the private key
+        let key = match config.SEAL_PRIVATE_KEY.0.trim() {
+            "" => None,
+            // Note: The key is not printed in error messages since it is secret
+            hex => match decode_hex(hex).map(<[u8; 32]>::try_from) {
+                Some(Ok(key)) => Some(Key(StaticSecret::from(key))),
+                _ => return Err(Error::new(ErrorKind::InvalidData, "invalid seal private key")),
+            },
+        };
+
+        // Remove decrypted messages that have been left over by a previous run
+        let spool = private::dir(config, Self::SUBDIR)?;
+        private::clear(&spool)?;
+
+        // Print the public key, so that it can be distributed to the producers
+        if let Some(Key(key)) = &key {
+            log::info!("Accepting sealed messages for public key: {}", encode_hex(PublicKey::from(key).as_bytes()));
+        }
+        Ok(Self { key, spool })
+    }
+
+    /// Generates a new key pair and returns the hex-encoded private and public key
+    pub fn keygen() -> Result<(String, String), Error> {
+        let mut key = [0; 32];
+        getrandom::getrandom(&mut key).map_err(Error::other)?;
+        let key = StaticSecret::from(key);
+        Ok((encode_hex(key.as_bytes()), encode_hex(PublicKey::from(&key).as_bytes())))
+    }
+
+    /// Decrypts a sealed message into the spool directory and returns the path of the decrypted envelope
+    ///
+    /// # Note
+    /// Messages that cannot be decrypted yield an error of kind [`ErrorKind::PermissionDenied`]. The decrypted envelope
+    /// must be removed via [`Self::remove`] once it has been processed.
+    pub fn unseal(&self, sealed: &Path) -> Result<PathBuf, Error> {
+        // Remove a stale spool file and create a new one
+        let Some(filename) = sealed.file_name() else {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        };
+        let unsealed = self.spool.join(filename).with_extension("sealed.msg");
+        self.remove(sealed)?;
+        let mut file = private::create(&unsealed)?;
+
+        // Decrypt the chunks
+        let result = self.decrypt(sealed, u64::MAX, |chunk| file.write_all(chunk));
+        if let Err(e) = result.and_then(|_| file.sync_all()) {
+            fs::remove_file(&unsealed)?;
+            return Err(e);
+        }
+        Ok(unsealed)
+    }
+
+    /// Decrypts the start of a sealed message that contains the envelope header
+    pub fn header(&self, sealed: &Path, limit: usize) -> Result<Vec<u8>, Error> {
+        let mut header = Vec::new();
+        let chunks = (limit as u64).div_ceil(CHUNK_SIZE as u64);
+        self.decrypt(sealed, chunks, |chunk| {
+            header.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        header.truncate(limit);
+        Ok(header)
+    }
+
+    /// Removes the decrypted envelope of a sealed message if it exists
+    pub fn remove(&self, sealed: &Path) -> Result<(), Error> {
+        let Some(filename) = sealed.file_name() else {
+            return Ok(());
+        };
+        match fs::remove_file(self.spool.join(filename).with_extension("sealed.msg")) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Decrypts up to `chunks` chunks of a sealed message and passes them to `sink`
+    fn decrypt<F>(&self, sealed: &Path, chunks: u64, mut sink: F) -> Result<(), Error>
+    where
+        F: FnMut(&[u8]) -> Result<(), Error>,
+    {
+        // Get the key
+        let Some(Key(key)) = &self.key else {
+            return Err(Error::new(ErrorKind::PermissionDenied, "sealed messages are not enabled"));
+        };
+        let invalid = || Error::new(ErrorKind::PermissionDenied, "cannot decrypt sealed message");
+
+        // Read the header
+        let mut file = File::open(sealed)?;
+        let mut header = [0; MAGIC.len() + 32];
+        file.read_exact(&mut header).map_err(|_| invalid())?;
+        let (magic, ephemeral) = header.split_at(MAGIC.len());
+        let (true, Ok(ephemeral)) = (magic == MAGIC, <[u8; 32]>::try_from(ephemeral)) else {
+            return Err(invalid());
+        };
+
+        // Derive the message key
+        let (ephemeral, public) = (PublicKey::from(ephemeral), PublicKey::from(key));
+        let shared = key.diffie_hellman(&ephemeral);
+        let true = shared.was_contributory() else {
+            return Err(invalid());
+        };
+        let salt = [ephemeral.as_bytes().as_slice(), public.as_bytes().as_slice()].concat();
+        let mut message_key = [0; 32];
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+        hkdf.expand(INFO, &mut message_key).map_err(|_| Error::other("failed to derive message key"))?;
+        let cipher = ChaCha20Poly1305::new(&message_key.into());
+
+        // Decrypt the chunks
+        let len = file.seek(SeekFrom::End(0))?.saturating_sub(header.len() as u64);
+        file.seek(SeekFrom::Start(header.len() as u64))?;
+        let (mut offset, mut index) = (0, 0);
+        while index < chunks {
+            // Read the chunk
+            let chunk_len = len.saturating_sub(offset).min((CHUNK_SIZE + TAG_SIZE) as u64);
+            // Note: A message that is truncated while it is read is invalid data, so that it is quarantined
+            let mut chunk = vec![0; chunk_len as usize];
+            match file.read_exact(&mut chunk) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    return Err(Error::new(ErrorKind::InvalidData, "sealed message has been truncated"));
+                }
+                result => result?,
+            }
+            offset = offset.saturating_add(chunk_len);
+
+            // Decrypt the chunk
+            let last = offset == len;
+            let plaintext = cipher.decrypt(&nonce(index, last), chunk.as_slice()).map_err(|_| invalid())?;
+            sink(&plaintext)?;
+            index = index.saturating_add(1);
+            if last {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates the nonce for the chunk with the given index
+fn nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0; 12];
+    let (counter, flag) = nonce.split_at_mut(11);
+    counter.split_at_mut(3).1.copy_from_slice(&index.to_be_bytes());
+    flag.fill(u8::from(last));
+    Nonce::from(nonce)
+}
+
+/// Encodes bytes as hex string
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
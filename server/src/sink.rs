@@ -0,0 +1,202 @@
+//! A matrix backend that writes the rendered messages to stdout or an outbox directory instead of sending them
+
+use crate::{
+    config::Config,
+    envelope::{Envelope, Metadata},
+    events::Relation,
+    log,
+    matrix::Backend,
+    media,
+    message::{Attachment, Message},
+    private, time,
+};
+use serde_json::{json, Value};
+use std::{
+    cell::Cell,
+    fs::{self, File},
+    io::{self, Error, ErrorKind, Write},
+    path::PathBuf,
+};
+
+/// The sink backend for staging environments and tests
+#[derive(Debug)]
+pub struct Sink {
+    /// The outbox directory, or `None` to write to stdout
+    outbox: Option<PathBuf>,
+    /// The sequence number of the last record
+    sequence: Cell<u64>,
+    /// The path to the state file with the sequence number of the last record
+    path: PathBuf,
+}
+impl Sink {
+    /// The subdirectory of the IPC directory that contains the state file
+    ///
+    /// # Note
+    /// The sequence number is persisted, so that the event IDs remain unique across restarts like the recorded events
+    pub const SUBDIR: &'static str = "sink";
+    /// The state file name within the subdirectory
+    const STATE_FILE: &'static str = "sink.state";
+
+    /// Creates a new sink backend and loads the sequence number of the last record if any
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        // Create the outbox directory if any
+        let outbox = Some(PathBuf::from(&config.SINK_PATH)).filter(|path| !path.as_os_str().is_empty());
+        match &outbox {
+            Some(outbox) => {
+                fs::create_dir_all(outbox)?;
+                log::info!("Writing messages to outbox: {}", outbox.display());
+            }
+            None => log::info!("Writing messages to stdout"),
+        }
+
+        // Load the sequence number
+        let path = private::dir(config, Self::SUBDIR)?.join(Self::STATE_FILE);
+        let sequence = match fs::read_to_string(&path) {
+            Ok(state) => state.trim().parse().map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(Self { outbox, sequence: Cell::new(sequence), path })
+    }
+
+    /// Writes a record and returns its synthetic event ID
+    ///
+    /// # Format
+    /// Every record is a JSON object with the synthetic `event_id`, the `event` type, i.e. `message`, `reaction` or
+    /// `redaction`, and the event specific fields. Records are written as single lines to stdout, or as
+    /// `<event_id>.json` files into the outbox directory.
+    fn write(&self, event: &str, record: Value, attachment: Option<(&str, &Attachment)>) -> Result<String, Error> {
+        // Create the event ID and persist its sequence number
+        let sequence = self.sequence.get().saturating_add(1);
+        private::replace(&self.path, sequence.to_string().as_bytes())?;
+        self.sequence.set(sequence);
+        let id = format!("sink-{}-{sequence}", time::now());
+        let record = Self::insert(record, "event_id", json!(format!("${id}")));
+        let mut record = Self::insert(record, "event", json!(event));
+
+        // Write to stdout if there is no outbox
+        let Some(outbox) = &self.outbox else {
+            let mut stdout = io::stdout().lock();
+            writeln!(stdout, "{record}")?;
+            stdout.flush()?;
+            return Ok(format!("${id}"));
+        };
+
+        // Copy the attachment into the outbox
+        if let Some((name, contents)) = attachment {
+            let filename = format!("{id}-{name}");
+            io::copy(&mut contents.open()?, &mut File::create(outbox.join(&filename))?)?;
+            if let Some(attachment) = record.get_mut("attachment") {
+                *attachment = Self::insert(attachment.take(), "file", json!(filename));
+            }
+        }
+
+        // Atomically write the record
+        let path = outbox.join(format!("{id}.json"));
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&record)?)?;
+        fs::rename(tmp, path)?;
+        Ok(format!("${id}"))
+    }
+
+    /// Renders a message
+    fn message(message: &Message) -> Result<Value, Error> {
+        let lossy = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        let record = match message {
+            Message::Plaintext { text } | Message::Notice { text } | Message::Emote { text } => {
+                json!({ "body": lossy(text) })
+            }
+            Message::Markdown { markdown } => json!({ "body": lossy(markdown) }),
+            Message::Html { html, fallback } => json!({ "body": lossy(fallback), "html": lossy(html) }),
+            Message::Reaction { target, reaction } => json!({ "target": target, "body": lossy(reaction) }),
+            Message::Redaction { target, reason } => json!({ "target": target, "body": lossy(reason) }),
+            Message::Raw { name, contents } => {
+                let mimetype = media::sniff(name, &contents.head(media::SNIFF_SIZE)?);
+                json!({ "attachment": { "name": name, "size": contents.len, "mimetype": mimetype } })
+            }
+        };
+        Ok(record)
+    }
+
+    /// Renders the message metadata
+    fn metadata(metadata: &Metadata) -> Value {
+        let Metadata {
+            dedup_key,
+            digest,
+            tag,
+            not_before,
+            expires,
+            priority,
+            event_key,
+            reply_to,
+            thread,
+            edit,
+            mentions,
+            ping_room,
+            thread_files,
+            received,
+            owner,
+            signature,
+            producer,
+        } = metadata;
+        json!({
+            "dedup_key": dedup_key,
+            "digest": digest,
+            "tag": tag,
+            "not_before": not_before,
+            "expires": expires,
+            "priority": priority.name(),
+            "event_key": event_key,
+            "reply_to": reply_to,
+            "thread": thread,
+            "edit": edit,
+            "mentions": mentions,
+            "ping_room": ping_room,
+            "thread_files": thread_files,
+            "received": received,
+            "owner": owner,
+            "signed": signature.is_some(),
+            "producer": producer,
+        })
+    }
+
+    /// Inserts a field into a JSON object
+    fn insert(mut object: Value, name: &str, value: Value) -> Value {
+        if let Some(object) = object.as_object_mut() {
+            object.insert(name.to_string(), value);
+        }
+        object
+    }
+
+    /// Renders a relation
+    fn relation(relation: &Relation) -> Value {
+        match relation {
+            Relation::Reply { event_id } => json!({ "type": "reply", "event_id": event_id }),
+            Relation::Thread { root } => json!({ "type": "thread", "event_id": root }),
+            Relation::Edit { event_id } => json!({ "type": "edit", "event_id": event_id }),
+        }
+    }
+}
+impl Backend for Sink {
+    fn send(&self, envelope: &Envelope, relation: Option<&Relation>) -> Result<Option<String>, Error> {
+        let record = Self::message(&envelope.message)?;
+        let record = Self::insert(record, "type", json!(envelope.message.kind()));
+        let record = Self::insert(record, "relation", relation.map(Self::relation).unwrap_or(Value::Null));
+        let record = Self::insert(record, "metadata", Self::metadata(&envelope.metadata));
+        let attachment = match &envelope.message {
+            Message::Raw { name, contents } => Some((name.as_str(), contents)),
+            _ => None,
+        };
+        self.write("message", record, attachment).map(Some)
+    }
+
+    fn react(&self, event_id: &str, reaction: &str) -> Result<(), Error> {
+        self.write("reaction", json!({ "target_event_id": event_id, "key": reaction }), None)?;
+        Ok(())
+    }
+
+    fn redact(&self, event_id: &str, reason: &str) -> Result<(), Error> {
+        self.write("redaction", json!({ "target_event_id": event_id, "reason": reason }), None)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,11 @@
+//! Time helpers
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Gets the current UNIX timestamp in seconds
+pub fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0,
+    }
+}
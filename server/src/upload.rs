@@ -0,0 +1,148 @@
+//! Preparation of attachments that exceed the upload limit
+
+use crate::{config::Config, message::Attachment, private};
+use flate2::{write::GzEncoder, Compression as GzLevel};
+use std::{
+    fs,
+    io::{self, BufWriter, Error, ErrorKind},
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// The compression for attachments that exceed the upload limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Attachments are not compressed
+    None,
+    /// Attachments are compressed with gzip
+    Gzip,
+    /// Attachments are compressed with zstd
+    Zstd,
+}
+impl Compression {
+    /// The file extension of compressed attachments
+    fn extension(self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Gzip => "gz",
+            Self::Zstd => "zst",
+        }
+    }
+}
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("invalid upload compression: {s}"))),
+        }
+    }
+}
+
+/// Compresses or splits attachments that exceed the upload limit
+#[derive(Debug)]
+pub struct Uploads {
+    /// The directory for temporary compressed attachments
+    spool: PathBuf,
+    /// The compression for attachments that exceed the upload limit
+    compression: Compression,
+    /// Whether attachments that exceed the upload limit are split into parts
+    split: bool,
+}
+impl Uploads {
+    /// The subdirectory of the IPC directory where compressed attachments are stored temporarily
+    pub const SUBDIR: &'static str = "upload";
+
+    /// Creates a new attachment preparer and removes stale compressed attachments
+    ///
+    /// # Note
+    /// The spool directory is only accessible by the server, so that producers cannot redirect or read the compressed
+    /// attachments
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        let spool = private::dir(config, Self::SUBDIR)?;
+        let this = Self { spool, compression: config.UPLOAD_COMPRESSION.parse()?, split: config.UPLOAD_SPLIT };
+        this.cleanup()?;
+        Ok(this)
+    }
+
+    /// Prepares an attachment for upload and returns the attachments to send in order
+    ///
+    /// # Note
+    /// Attachments within the limit are returned as they are. Larger attachments are compressed if configured, and
+    /// split into parts (`name.001`, `name.002`, ...) if configured and they are still too large. If an attachment
+    /// cannot be brought within the limit, an error of kind [`ErrorKind::FileTooLarge`] is returned.
+    pub fn prepare(&self, name: &str, contents: &Attachment, limit: u64) -> Result<Vec<(String, Attachment)>, Error> {
+        // Send small attachments as they are
+        if contents.len <= limit {
+            return Ok(vec![(name.to_string(), contents.clone())]);
+        }
+
+        // Compress the attachment if configured
+        let (name, contents) = match self.compression {
+            Compression::None => (name.to_string(), contents.clone()),
+            compression => self.compress(name, contents, compression)?,
+        };
+        if contents.len <= limit {
+            return Ok(vec![(name, contents)]);
+        }
+
+        // Split the attachment if configured
+        let (true, 1..) = (self.split, limit) else {
+            return Err(Error::from(ErrorKind::FileTooLarge));
+        };
+        let step = usize::try_from(limit).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let mut parts = Vec::new();
+        for (index, offset) in (0..contents.len).step_by(step).enumerate() {
+            let part = Attachment {
+                path: contents.path.clone(),
+                offset: contents.offset.saturating_add(offset),
+                len: contents.len.saturating_sub(offset).min(limit),
+            };
+            parts.push((format!("{name}.{:03}", index.saturating_add(1)), part));
+        }
+        Ok(parts)
+    }
+
+    /// Removes all temporarily compressed attachments
+    pub fn cleanup(&self) -> Result<(), Error> {
+        private::clear(&self.spool)
+    }
+
+    /// Compresses an attachment into the spool directory
+    fn compress(
+        &self,
+        name: &str,
+        contents: &Attachment,
+        compression: Compression,
+    ) -> Result<(String, Attachment), Error> {
+        // Create the temporary file
+        // Note: The attachment name is not used for the path since it is untrusted
+        let extension = compression.extension();
+        let path = self.spool.join(format!("attachment.{extension}"));
+        let file = BufWriter::new(private::create(&path)?);
+
+        // Compress the attachment
+        let mut source = contents.open()?;
+        let file = match compression {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(file, GzLevel::default());
+                io::copy(&mut source, &mut encoder)?;
+                encoder.finish()?
+            }
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(file, 0)?;
+                io::copy(&mut source, &mut encoder)?;
+                encoder.finish()?
+            }
+            Compression::None => file,
+        };
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        // Reference the compressed attachment
+        let len = fs::metadata(&path)?.len();
+        Ok((format!("{name}.{extension}"), Attachment { path, offset: 0, len }))
+    }
+}
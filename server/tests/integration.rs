@@ -0,0 +1,824 @@
+//! End-to-end tests that drive the `sendmatrix` client against a running `sendmatrix-server` with a scripted fake
+//! `matrix-commander-rs`
+//!
+//! # Note
+//! The fake is a shell script, so the tests are only supported on unix
+#![cfg(unix)]
+
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fs::{self, File},
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tempfile::TempDir;
+
+/// The fake `matrix-commander-rs`, which records the argv and stdin of every call into `$FAKE_LOG` and then succeeds,
+/// succeeds after a second, fails or hangs depending on `$FAKE_MODE`
+const FAKE_COMMANDER: &str = r#"#!/bin/sh
+if [ "$1" = "--whoami" ]; then
+    echo "@bot:example.org"
+    exit 0
+fi
+
+# Record the call; the args file is renamed last, so that a recorded call is always complete
+CALL=$(printf '%04d' "$(ls "$FAKE_LOG" | grep -c '\.args$')")
+cat > "$FAKE_LOG/$CALL.stdin"
+printf '%s\n' "$@" > "$FAKE_LOG/$CALL.args.tmp"
+mv "$FAKE_LOG/$CALL.args.tmp" "$FAKE_LOG/$CALL.args"
+
+case "$FAKE_MODE" in
+    slow) sleep 1; exit 0 ;;
+    fail) exit 1 ;;
+    hang) echo $$ > "$FAKE_LOG/hang.pid"; exec sleep 600 ;;
+    *) exit 0 ;;
+esac
+"#;
+
+/// How long to wait for the server; it polls the IPC directory every 3 seconds
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A recorded call of the fake `matrix-commander-rs`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Call {
+    /// The arguments
+    args: Vec<String>,
+    /// The data written to stdin
+    stdin: Vec<u8>,
+}
+impl Call {
+    /// The stdin data as text
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.stdin).into_owned()
+    }
+
+    /// Whether the call has the given argument
+    fn has(&self, arg: &str) -> bool {
+        self.args.iter().any(|arg_| arg_ == arg)
+    }
+
+    /// The value that follows the given argument
+    fn value(&self, arg: &str) -> Option<&str> {
+        let position = self.args.iter().position(|arg_| arg_ == arg)?;
+        self.args.get(position + 1).map(String::as_str)
+    }
+}
+
+/// A temporary IPC directory with a server and a fake `matrix-commander-rs`
+#[derive(Debug)]
+struct Harness {
+    /// The temporary root directory
+    root: TempDir,
+    /// The running server if any
+    server: Option<Child>,
+}
+impl Harness {
+    /// Creates a new harness with an empty IPC directory
+    fn new() -> Self {
+        let root = tempfile::tempdir().expect("failed to create temp dir");
+        for dir in ["ipc", "calls", "files"] {
+            fs::create_dir(root.path().join(dir)).expect("failed to create directory");
+        }
+
+        // Install the fake
+        let fake = root.path().join("matrix-commander-rs");
+        fs::write(&fake, FAKE_COMMANDER).expect("failed to write fake matrix-commander-rs");
+        fs::set_permissions(&fake, fs::Permissions::from_mode(0o755)).expect("failed to make fake executable");
+        Self { root, server: None }
+    }
+
+    /// The IPC directory
+    fn ipc(&self) -> PathBuf {
+        self.root.path().join("ipc")
+    }
+
+    /// The outbox directory for the sink backend
+    fn outbox(&self) -> PathBuf {
+        self.root.path().join("outbox")
+    }
+
+    /// Writes a payload file and returns its path
+    fn file(&self, name: &str, contents: &[u8]) -> PathBuf {
+        let path = self.root.path().join("files").join(name);
+        fs::write(&path, contents).expect("failed to write payload file");
+        path
+    }
+
+    /// Starts the server with the given fake mode and additional environment variables
+    fn start(&mut self, mode: &str, env: &[(&str, &str)]) {
+        let server = Command::new(env!("CARGO_BIN_EXE_sendmatrix-server"))
+            .env_clear()
+            .env("PATH", env::var_os("PATH").unwrap_or_default())
+            .env("IPC_PATH", self.ipc())
+            .env("MATRIX_PATH", self.root.path().join("matrix-commander-rs"))
+            .env("FAKE_LOG", self.root.path().join("calls"))
+            .env("FAKE_MODE", mode)
+            .envs(env.iter().copied())
+            .stdout(Stdio::null())
+            .stderr(File::create(self.root.path().join("server.log")).expect("failed to create server log"))
+            .spawn()
+            .expect("failed to start sendmatrix-server");
+        self.server = Some(server);
+    }
+
+    /// Waits until the server exits on its own
+    fn wait_exit(&mut self) -> ExitStatus {
+        let server = self.server.as_mut().expect("server is not running");
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT {
+            if let Some(status) = server.try_wait().expect("failed to poll server") {
+                self.server = None;
+                return status;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("server did not exit\n{}", self.log());
+    }
+
+    /// Stops the server and a hanging fake if any
+    fn stop(&mut self) {
+        if let Some(mut server) = self.server.take() {
+            let _ = server.kill();
+            let _ = server.wait();
+        }
+        if let Ok(pid) = fs::read_to_string(self.root.path().join("calls").join("hang.pid")) {
+            let _ = Command::new("kill").arg(pid.trim()).status();
+            let _ = fs::remove_file(self.root.path().join("calls").join("hang.pid"));
+        }
+    }
+
+    /// Sends a message via the client
+    fn send(&self, args: &[&str]) {
+        let ipc_path = format!("--ipc-path={}", self.ipc().display());
+        let output = Command::new(client())
+            .args(args)
+            .arg(ipc_path)
+            .stdin(Stdio::null())
+            .output()
+            .expect("failed to start sendmatrix");
+        assert!(output.status.success(), "sendmatrix {args:?} failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    /// Sends a message via the client and returns the path of the published message
+    fn publish(&self, args: &[&str]) -> PathBuf {
+        let before = self.messages();
+        self.send(args);
+        let mut published = self.messages().into_iter().filter(|path| !before.contains(path));
+        published.next().expect("no message has been published")
+    }
+
+    /// The names of the quarantined files
+    fn quarantine(&self) -> Vec<String> {
+        let Ok(entries) = fs::read_dir(self.ipc().join("quarantine")) else {
+            return Vec::new();
+        };
+        let mut names: Vec<_> =
+            entries.map(|entry| entry.expect("failed to read quarantine entry").file_name()).collect();
+        names.sort();
+        names.into_iter().map(|name| name.to_string_lossy().into_owned()).collect()
+    }
+
+    /// The recorded calls in order
+    fn calls(&self) -> Vec<Call> {
+        let calls = self.root.path().join("calls");
+        let mut names: Vec<_> = fs::read_dir(&calls)
+            .expect("failed to list calls")
+            .map(|entry| entry.expect("failed to read call").file_name().to_string_lossy().into_owned())
+            .filter_map(|name| name.strip_suffix(".args").map(str::to_string))
+            .collect();
+        names.sort();
+
+        // Load the calls
+        names
+            .into_iter()
+            .map(|name| {
+                let args = fs::read_to_string(calls.join(format!("{name}.args"))).expect("failed to read args");
+                let stdin = fs::read(calls.join(format!("{name}.stdin"))).expect("failed to read stdin");
+                Call { args: args.lines().map(str::to_string).collect(), stdin }
+            })
+            .collect()
+    }
+
+    /// Waits until the fake has been called at least `count` times and returns the calls
+    fn wait_calls(&self, count: usize) -> Vec<Call> {
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT {
+            let calls = self.calls();
+            if calls.len() >= count {
+                return calls;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("expected {count} calls, got {:?}\n{}", self.calls(), self.log());
+    }
+
+    /// The records written by the sink backend in order
+    fn records(&self) -> Vec<Value> {
+        let Ok(entries) = fs::read_dir(self.outbox()) else {
+            return Vec::new();
+        };
+        let paths = entries.map(|entry| entry.expect("failed to read outbox entry").path());
+        let paths = paths.filter(|path| path.extension().is_some_and(|ext| ext == "json"));
+        let mut records: Vec<Value> = paths
+            .map(|path| {
+                serde_json::from_slice(&fs::read(path).expect("failed to read record")).expect("invalid record")
+            })
+            .collect();
+
+        // Order the records by their sequence number, which is the last part of the event ID
+        let sequence = |record: &Value| {
+            let event_id = record["event_id"].as_str().unwrap_or_default();
+            event_id.rsplit('-').next().and_then(|sequence| sequence.parse::<u64>().ok())
+        };
+        records.sort_by_key(sequence);
+        records
+    }
+
+    /// Waits until the sink backend has written at least `count` records and returns the records
+    fn wait_records(&self, count: usize) -> Vec<Value> {
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT {
+            let records = self.records();
+            if records.len() >= count {
+                return records;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("expected {count} records, got {:?}\n{}", self.records(), self.log());
+    }
+
+    /// Waits until the IPC directory contains no more messages
+    fn wait_drained(&self) {
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT {
+            if self.messages().is_empty() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("IPC directory has not been drained: {:?}\n{}", self.messages(), self.log());
+    }
+
+    /// The published messages within the IPC directory
+    fn messages(&self) -> Vec<PathBuf> {
+        let entries = fs::read_dir(self.ipc()).expect("failed to list IPC directory");
+        let paths = entries.map(|entry| entry.expect("failed to read IPC entry").path());
+        paths.filter(|path| path.extension().is_some_and(|ext| ext == "msg" || ext == "sealed")).collect()
+    }
+
+    /// The server log
+    fn log(&self) -> String {
+        fs::read_to_string(self.root.path().join("server.log")).unwrap_or_default()
+    }
+}
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// The current UNIX timestamp
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("system time is before 1970").as_secs()
+}
+
+/// Generates a seal key pair via the server and returns the private key and the public key
+fn seal_keygen() -> (String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_sendmatrix-server")).arg("--seal-keygen").output().expect("keygen");
+    let output = String::from_utf8(output.stdout).expect("invalid key pair");
+    let key = |name: &str| {
+        let line = output.lines().find_map(|line| line.strip_prefix(name)).expect("missing key");
+        line.to_string()
+    };
+    (key("SEAL_PRIVATE_KEY="), key("SEAL_PUBLIC_KEY="))
+}
+
+/// Replaces the first occurrence of `from` within a file with `to`
+fn tamper(path: &Path, from: &str, to: &str) {
+    let contents = fs::read(path).expect("failed to read message");
+    let position =
+        contents.windows(from.len()).position(|window| window == from.as_bytes()).expect("nothing to tamper");
+    let tampered = [&contents[..position], to.as_bytes(), &contents[position + from.len()..]].concat();
+    fs::write(path, tampered).expect("failed to write message");
+}
+
+/// The path to the `sendmatrix` client, which is built next to the server
+fn client() -> PathBuf {
+    let server = Path::new(env!("CARGO_BIN_EXE_sendmatrix-server"));
+    let client = server.with_file_name(format!("sendmatrix{}", env::consts::EXE_SUFFIX));
+    if !client.exists() {
+        // Build the client if only the server package is tested
+        let status = Command::new(env!("CARGO"))
+            .args(["build", "--package", "sendmatrix"])
+            .status()
+            .expect("failed to build sendmatrix");
+        assert!(status.success(), "failed to build sendmatrix");
+    }
+    client
+}
+
+#[test]
+fn message_types() {
+    let mut harness = Harness::new();
+    let attachment: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+    let raw = harness.file("data.bin", &attachment);
+    harness.send(&["--type=text", "--payload=plain message"]);
+    harness.send(&["--type=markdown", "--payload=**markdown message**"]);
+    harness.send(&["--type=notice", "--payload=notice message"]);
+    harness.send(&["--type=emote", "--payload=emote message"]);
+    harness.send(&["--type=html", "--payload=<b>html message</b>", "--fallback=html message"]);
+    harness.send(&["--type=raw", &format!("--payload={}", raw.display())]);
+    harness.start("ok", &[]);
+
+    // Match the calls by their contents since messages of the same priority are not ordered
+    let calls = harness.wait_calls(6);
+    harness.wait_drained();
+    assert_eq!(calls.len(), 6, "unexpected calls: {calls:?}");
+    let find = |text: &str| calls.iter().find(|call| call.text() == text).unwrap_or_else(|| panic!("no call: {text}"));
+    assert_eq!(find("plain message").args, ["--message", "-"]);
+    assert_eq!(find("**markdown message**").args, ["--message", "-", "--markdown"]);
+    assert_eq!(find("notice message").args, ["--message", "-", "--notice"]);
+    assert_eq!(find("emote message").args, ["--message", "-", "--emote"]);
+    assert_eq!(find("<b>html message</b>").args, ["--message", "-", "--html"]);
+
+    // Attachments are streamed with their name and content type
+    let file = calls.iter().find(|call| call.has("--file")).expect("no attachment call");
+    assert_eq!(file.value("--file-name"), Some("data.bin"));
+    assert_eq!(file.value("--mime"), Some("application/octet-stream"));
+    assert_eq!(file.stdin, attachment);
+}
+
+#[test]
+fn unsupported_updates() {
+    let mut harness = Harness::new();
+    harness.start("ok", &[]);
+    harness.send(&["--type=text", "--payload=disk 91% full", "--event-key=disk"]);
+    harness.wait_calls(1);
+
+    // `matrix-commander-rs` reports no event IDs, so related messages, reactions and redactions are quarantined instead
+    // of being sent unrelated or consumed
+    harness.send(&["--type=text", "--payload=reply", "--reply-to=disk"]);
+    harness.send(&["--type=text", "--payload=thread", "--thread=disk"]);
+    harness.send(&["--type=text", "--payload=edit", "--edit=disk"]);
+    harness.send(&["react", "--target=disk", "--reaction=ok"]);
+    harness.send(&["redact", "--target=disk", "--reason=false alarm"]);
+    harness.wait_drained();
+    harness.send(&["--type=text", "--payload=done"]);
+    let calls = harness.wait_calls(2);
+    assert_eq!(calls.len(), 2, "unexpected calls: {calls:?}");
+    assert_eq!(calls[1].text(), "done");
+    assert_eq!(harness.quarantine().len(), 5, "updates have not been quarantined");
+}
+
+#[test]
+fn reactions_and_redactions() {
+    let mut harness = Harness::new();
+    let outbox = harness.outbox();
+    harness.start("ok", &[("BACKEND", "sink"), ("SINK_PATH", outbox.to_str().expect("non-UTF-8 outbox"))]);
+    harness.send(&["--type=text", "--payload=disk 91% full", "--event-key=disk"]);
+    let records = harness.wait_records(1);
+    let event_id = records[0]["event_id"].as_str().expect("no event ID").to_string();
+
+    // Replies, reactions and redactions refer to the recorded event
+    harness.send(&["--type=text", "--payload=cleaning up", "--reply-to=disk"]);
+    harness.wait_records(2);
+    harness.send(&["react", "--target=disk", "--reaction=ok"]);
+    harness.wait_records(3);
+    harness.send(&["redact", "--target=disk", "--reason=false alarm"]);
+    let records = harness.wait_records(4);
+    harness.wait_drained();
+    assert_eq!(records.len(), 4, "unexpected records: {records:?}");
+    assert_eq!(records[1]["relation"]["type"], "reply");
+    assert_eq!(records[1]["relation"]["event_id"], event_id.as_str());
+    assert_eq!(records[2]["event"], "reaction");
+    assert_eq!(records[2]["target_event_id"], event_id.as_str());
+    assert_eq!(records[2]["key"], "ok");
+    assert_eq!(records[3]["event"], "redaction");
+    assert_eq!(records[3]["target_event_id"], event_id.as_str());
+    assert_eq!(records[3]["reason"], "false alarm");
+    let mode =
+        fs::metadata(harness.ipc().join("events")).expect("failed to stat events directory").permissions().mode();
+    assert_eq!(mode & 0o777, 0o700, "events directory is accessible by producers");
+
+    // The keys of a redacted event are forgotten, so later updates are dropped
+    harness.send(&["react", "--target=disk", "--reaction=ok"]);
+    harness.wait_drained();
+    harness.send(&["--type=text", "--payload=done"]);
+    let records = harness.wait_records(5);
+    assert_eq!(records.len(), 5, "unexpected records: {records:?}");
+    assert_eq!(records[4]["body"], "done");
+    harness.stop();
+
+    // Event IDs remain unique and ordered across restarts
+    harness.start("ok", &[("BACKEND", "sink"), ("SINK_PATH", outbox.to_str().expect("non-UTF-8 outbox"))]);
+    harness.send(&["--type=text", "--payload=restarted"]);
+    let records = harness.wait_records(6);
+    assert_eq!(records.len(), 6, "unexpected records: {records:?}");
+    assert_eq!(records[5]["body"], "restarted");
+    let event_ids: BTreeSet<_> = records.iter().map(|record| record["event_id"].as_str()).collect();
+    assert_eq!(event_ids.len(), 6, "duplicate event IDs: {records:?}");
+}
+
+#[test]
+fn ordering() {
+    let mut harness = Harness::new();
+    let first = harness.file("first.log", b"first attachment");
+    let second = harness.file("second.log", b"second attachment");
+    harness.send(&["--type=text", "--payload=low", "--priority=low"]);
+    harness.send(&["--type=text", "--payload=normal"]);
+    harness.send(&[
+        "--type=markdown",
+        "--payload=report",
+        &format!("--file={}", first.display()),
+        &format!("--file={}", second.display()),
+    ]);
+    harness.send(&["--type=text", "--payload=critical", "--priority=critical"]);
+    harness.start("ok", &[]);
+
+    // Critical messages are sent first and low priority messages last as notice
+    let calls = harness.wait_calls(6);
+    assert_eq!(calls.len(), 6, "unexpected calls: {calls:?}");
+    assert!(calls[0].text().ends_with("critical"), "critical message is not first: {calls:?}");
+    assert!(calls[5].text() == "low" && calls[5].has("--notice"), "low message is not last: {calls:?}");
+
+    // The attachments of a group follow their caption in order
+    let caption = calls.iter().position(|call| call.text() == "report").expect("no caption call");
+    assert_eq!(calls[caption + 1].value("--file-name"), Some("first.log"));
+    assert_eq!(calls[caption + 1].stdin, b"first attachment");
+    assert_eq!(calls[caption + 2].value("--file-name"), Some("second.log"));
+    assert_eq!(calls[caption + 2].stdin, b"second attachment");
+}
+
+#[test]
+fn late_priority() {
+    let mut harness = Harness::new();
+    for index in 0..6 {
+        harness.send(&["--type=text", &format!("--payload=backlog {index}")]);
+    }
+    harness.start("slow", &[]);
+
+    // A critical message that arrives while the backlog is processed is sent before the rest of the backlog
+    harness.wait_calls(1);
+    harness.send(&["--type=text", "--payload=critical", "--priority=critical"]);
+    let calls = harness.wait_calls(7);
+    harness.wait_drained();
+    let critical = calls.iter().position(|call| call.text().ends_with("critical")).expect("no critical call");
+    assert!(critical <= 2, "critical message has been sent after the backlog: {calls:?}");
+}
+
+#[test]
+fn signatures() {
+    let mut harness = Harness::new();
+    let key = harness.file("backup.key", b"00112233445566778899aabbccddeeff\n");
+    let key_file = format!("--key-file={}", key.display());
+    let signed = harness.publish(&["--type=text", "--payload=signed message", "--producer=backup", &key_file]);
+    let contents = fs::read(&signed).expect("failed to read signed message");
+
+    // Tamper with the payload and the header of signed messages
+    let payload = harness.publish(&["--type=text", "--payload=signed payload", "--producer=backup", &key_file]);
+    tamper(&payload, "signed payload", "forged payload");
+    let header = harness.publish(&["--type=text", "--payload=signed header", "--producer=backup", &key_file]);
+    tamper(&header, "type=plaintext", "type=markdown");
+    harness.start("ok", &[("PRODUCER_KEYS", "backup=00112233445566778899aabbccddeeff"), ("PRODUCER_PREFIX", "true")]);
+
+    // Only the intact message is verified and sent; the tampered messages are quarantined
+    let calls = harness.wait_calls(1);
+    harness.wait_drained();
+    assert_eq!(calls.len(), 1, "unexpected calls: {calls:?}");
+    assert!(calls[0].text().contains("backup") && calls[0].text().ends_with("signed message"), "{calls:?}");
+    let mut tampered = [&payload, &header].map(|path| path.file_name().unwrap().to_string_lossy().into_owned());
+    tampered.sort();
+    assert_eq!(harness.quarantine(), tampered, "tampered messages have not been quarantined");
+    let mode =
+        fs::metadata(harness.ipc().join("signed")).expect("failed to stat signed directory").permissions().mode();
+    assert_eq!(mode & 0o777, 0o700, "signed directory is accessible by producers");
+    assert!(!harness.ipc().join("signed").read_dir().is_ok_and(|mut entries| entries.next().is_some()));
+    harness.stop();
+
+    // A replayed message is quarantined, even after a restart
+    fs::write(harness.ipc().join("replayed.msg"), &contents).expect("failed to replay message");
+    harness.start("ok", &[("PRODUCER_KEYS", "backup=00112233445566778899aabbccddeeff")]);
+    harness.wait_drained();
+    assert!(harness.quarantine().contains(&"replayed.msg".to_string()), "replayed message has not been quarantined");
+    assert!(harness.log().contains("replayed message"), "unexpected quarantine reason: {}", harness.log());
+    harness.stop();
+
+    // A stale message is quarantined
+    let stale = harness.publish(&["--type=text", "--payload=stale message", "--producer=backup", &key_file]);
+    thread::sleep(Duration::from_secs(2));
+    harness.start("ok", &[("PRODUCER_KEYS", "backup=00112233445566778899aabbccddeeff"), ("SIGNATURE_MAX_AGE", "1")]);
+    harness.wait_drained();
+    let stale = stale.file_name().unwrap().to_string_lossy().into_owned();
+    assert!(harness.quarantine().contains(&stale), "stale message has not been quarantined");
+    assert!(harness.log().contains("stale signature"), "unexpected quarantine reason: {}", harness.log());
+    assert_eq!(harness.calls().len(), 1, "unexpected calls: {:?}", harness.calls());
+}
+
+#[test]
+fn sealed() {
+    let mut harness = Harness::new();
+    let (private, public) = seal_keygen();
+    let (_, wrong) = seal_keygen();
+    let seal_key = format!("--seal-key={}", harness.file("server.pub", public.as_bytes()).display());
+    let wrong_key = format!("--seal-key={}", harness.file("wrong.pub", wrong.as_bytes()).display());
+
+    // The priority and the schedule are read from the sealed header, while the IPC file only contains ciphertext
+    harness.send(&["--type=text", "--payload=sealed normal", &seal_key]);
+    harness.send(&["--type=text", "--payload=sealed delayed", "--priority=critical", "--delay=5s", &seal_key]);
+    let critical = harness.publish(&["--type=text", "--payload=sealed critical", "--priority=critical", &seal_key]);
+    let contents = fs::read(&critical).expect("failed to read sealed message");
+    assert!(contents.starts_with(b"SMSEAL01"), "message has not been sealed");
+    assert!(!contents.windows(8).any(|window| window == b"critical"), "sealed message contains plaintext");
+
+    // Seal a message to the wrong key and flip a ciphertext byte of another one
+    let wrong = harness.publish(&["--type=text", "--payload=wrong key", &wrong_key]);
+    let flipped = harness.publish(&["--type=text", "--payload=flipped byte", &seal_key]);
+    let mut contents = fs::read(&flipped).expect("failed to read sealed message");
+    let last = contents.len() - 20;
+    contents[last] ^= 0x01;
+    fs::write(&flipped, contents).expect("failed to write sealed message");
+    harness.start("ok", &[("SEAL_PRIVATE_KEY", &private)]);
+
+    // The critical message is sent first and the delayed message once it is due
+    let calls = harness.wait_calls(3);
+    harness.wait_drained();
+    assert_eq!(calls.len(), 3, "unexpected calls: {calls:?}");
+    assert!(calls[0].text().ends_with("sealed critical"), "critical message is not first: {calls:?}");
+    assert_eq!(calls[1].text(), "sealed normal");
+    assert!(calls[2].text().ends_with("sealed delayed"), "delayed message is not last: {calls:?}");
+
+    // Messages that cannot be decrypted are quarantined as they are
+    let mut undecryptable = [&wrong, &flipped].map(|path| path.file_name().unwrap().to_string_lossy().into_owned());
+    undecryptable.sort();
+    assert_eq!(harness.quarantine(), undecryptable, "undecryptable messages have not been quarantined");
+    assert!(!harness.ipc().join("unsealed").read_dir().is_ok_and(|mut entries| entries.next().is_some()));
+    let mode =
+        fs::metadata(harness.ipc().join("unsealed")).expect("failed to stat unsealed directory").permissions().mode();
+    assert_eq!(mode & 0o777, 0o700, "unsealed directory is accessible by producers");
+}
+
+#[test]
+fn scheduling() {
+    let mut harness = Harness::new();
+    harness.start("ok", &[]);
+    harness.send(&["--type=text", "--payload=immediate"]);
+    harness.wait_calls(1);
+
+    // Scheduled messages are held back until they are due
+    let start = Instant::now();
+    harness.send(&["--type=text", "--payload=at", &format!("--at=@{}", now() + 8)]);
+    harness.send(&["--type=text", "--payload=delayed", "--delay=2s"]);
+    let calls = harness.wait_calls(2);
+    assert!(start.elapsed() >= Duration::from_secs(2), "delayed message has been sent early");
+    assert_eq!(calls[1].text(), "delayed");
+    let calls = harness.wait_calls(3);
+    assert!(start.elapsed() >= Duration::from_secs(7), "scheduled message has been sent early");
+    assert_eq!(calls[2].text(), "at");
+}
+
+#[test]
+fn deduplication() {
+    let mut harness = Harness::new();
+    for _ in 0..3 {
+        harness.send(&["--type=text", "--payload=disk 91% full"]);
+    }
+    harness.send(&["--type=text", "--payload=backup failed at 01:00", "--dedup-key=backup"]);
+    harness.send(&["--type=text", "--payload=backup failed at 02:00", "--dedup-key=backup"]);
+    harness.start("ok", &[("DEDUP_WINDOW", "3")]);
+
+    // Repetitions are suppressed and summarized once the window has elapsed
+    let calls = harness.wait_calls(4);
+    harness.wait_drained();
+    let texts: Vec<_> = calls.iter().map(Call::text).collect();
+    assert_eq!(texts.len(), 4, "unexpected calls: {calls:?}");
+    assert_eq!(texts.iter().filter(|text| *text == "disk 91% full").count(), 1, "{texts:?}");
+    assert_eq!(texts.iter().filter(|text| text.starts_with("backup failed")).count(), 1, "{texts:?}");
+    assert!(texts.contains(&String::from("Previous message repeated 2 times: disk 91% full")), "{texts:?}");
+    assert!(texts.iter().any(|text| text.starts_with("Previous message repeated 1 times: backup failed")), "{texts:?}");
+
+    // The state is kept in a subdirectory that is only accessible by the server
+    let mode = fs::metadata(harness.ipc().join("dedup")).expect("failed to stat dedup directory").permissions().mode();
+    assert_eq!(mode & 0o777, 0o700, "dedup directory is accessible by producers");
+    assert!(harness.ipc().join("dedup/dedup.state").is_file(), "dedup state has not been persisted");
+}
+
+#[test]
+fn expiry() {
+    let mut harness = Harness::new();
+    harness.send(&["--type=text", "--payload=short-lived", "--ttl=1s"]);
+    let stale = harness.publish(&["--type=text", "--payload=stale"]);
+    let modified = SystemTime::now() - Duration::from_secs(7200);
+    File::options()
+        .write(true)
+        .open(&stale)
+        .and_then(|file| file.set_modified(modified))
+        .expect("failed to age message");
+    harness.send(&["--type=text", "--payload=fresh"]);
+    thread::sleep(Duration::from_secs(2));
+    harness.start("ok", &[("MAX_AGE", "3600")]);
+
+    // Expired messages are moved aside and summarized once the queue has been drained
+    let calls = harness.wait_calls(2);
+    harness.wait_drained();
+    assert_eq!(calls.len(), 2, "unexpected calls: {calls:?}");
+    assert_eq!(calls[0].text(), "fresh");
+    assert_eq!(calls[1].text(), "2 messages expired before they could be sent");
+    let expired = fs::read_dir(harness.ipc().join("expired")).expect("failed to list expired messages").count();
+    assert_eq!(expired, 2, "expired messages have not been moved");
+}
+
+#[test]
+fn digest() {
+    // Flush the digest at the next full minute that leaves enough time to hold the messages back
+    let mut harness = Harness::new();
+    let flush = (now() + 10) / 60 * 60 + 60;
+    let schedule = format!("daily@{:02}:{:02}", flush % 86400 / 3600, flush % 3600 / 60);
+    harness.start("ok", &[("DIGEST_SCHEDULE", &schedule)]);
+    harness.send(&["--type=text", "--payload=backup done", "--digest=true", "--tag=*backup*", "--priority=high"]);
+    harness.send(&["--type=text", "--payload=sent immediately"]);
+
+    // Digest messages are held back, while other messages are sent immediately
+    let calls = harness.wait_calls(1);
+    harness.wait_drained();
+    assert_eq!(calls[0].text(), "sent immediately");
+    let held = fs::read_dir(harness.ipc().join("digest")).expect("failed to list held messages").count();
+    assert_eq!(held, 1, "digest message has not been held back");
+    fs::write(harness.ipc().join("digest").join("broken.msg"), b"no header\n\n").expect("failed to write entry");
+
+    // The held messages are sent as one digest with literal tags and priority prefixes, and unreadable messages are
+    // quarantined
+    thread::sleep(Duration::from_secs(flush.saturating_sub(now())));
+    let calls = harness.wait_calls(2);
+    assert_eq!(calls.len(), 2, "unexpected calls: {calls:?}");
+    assert_eq!(calls[1].text(), "**Digest** (1 messages)\n\n### \\*backup\\*\n- ⚠️ backup done\n");
+    assert_eq!(harness.quarantine(), ["broken.msg"]);
+    assert_eq!(fs::read_dir(harness.ipc().join("digest")).expect("failed to list held messages").count(), 0);
+}
+
+#[test]
+fn size_limit() {
+    let mut harness = Harness::new();
+    let large = harness.file("large.bin", &[0x42; 4096]);
+    harness.start("ok", &[("UPLOAD_SIZE_MAX", "1024")]);
+
+    // Attachments that exceed the limit are replaced by a notice
+    harness.send(&["--type=raw", &format!("--payload={}", large.display())]);
+    let calls = harness.wait_calls(1);
+    assert_eq!(calls[0].args, ["--message", "-", "--notice"]);
+    assert_eq!(calls[0].text(), "Attachment `large.bin` is too large to be sent (4096 of 1024 bytes)");
+    harness.wait_drained();
+    harness.stop();
+
+    // Attachments that exceed the limit are split into parts if configured
+    harness.start("ok", &[("UPLOAD_SIZE_MAX", "1024"), ("UPLOAD_SPLIT", "true")]);
+    harness.send(&["--type=raw", &format!("--payload={}", large.display())]);
+    let calls = harness.wait_calls(5);
+    let parts = calls.get(1..).expect("missing part calls");
+    assert_eq!(parts.len(), 4, "unexpected calls: {calls:?}");
+    for (index, part) in parts.iter().enumerate() {
+        assert_eq!(part.value("--file-name"), Some(format!("large.bin.{:03}", index + 1).as_str()));
+        assert_eq!(part.stdin, [0x42; 1024]);
+    }
+    let mode =
+        fs::metadata(harness.ipc().join("upload")).expect("failed to stat upload directory").permissions().mode();
+    assert_eq!(mode & 0o777, 0o700, "upload directory is accessible by producers");
+}
+
+#[test]
+fn failing_backend() {
+    let mut harness = Harness::new();
+    harness.send(&["--type=text", "--payload=retry me"]);
+
+    // The server terminates and keeps the message if `matrix-commander-rs` fails
+    harness.start("fail", &[]);
+    let status = harness.wait_exit();
+    assert!(!status.success(), "server did not fail");
+    assert_eq!(harness.messages().len(), 1, "message has been removed");
+    let health = fs::read_to_string(harness.ipc().join("health/health.state")).expect("failed to read health status");
+    assert!(health.contains("last-error="), "error has not been reported: {health}");
+    assert!(health.contains("failed="), "failed message has not been reported: {health}");
+    let mode =
+        fs::metadata(harness.ipc().join("health")).expect("failed to stat health directory").permissions().mode();
+    assert_eq!(mode & 0o777, 0o700, "health directory is accessible by producers");
+
+    // The message is delivered once the backend works again and counted as retry
+    let metrics = harness.root.path().join("sendmatrix.prom");
+    harness.start("ok", &[("METRICS_PATH", metrics.to_str().expect("invalid metrics path"))]);
+    let calls = harness.wait_calls(2);
+    harness.wait_drained();
+    assert_eq!(calls[0], calls[1]);
+    assert_eq!(calls[1].text(), "retry me");
+    let (start, retried) = (Instant::now(), r#"sendmatrix_messages_retried_total{type="plaintext"} 1"#);
+    while !fs::read_to_string(&metrics).is_ok_and(|metrics| metrics.contains(retried)) && start.elapsed() < TIMEOUT {
+        thread::sleep(Duration::from_millis(100));
+    }
+    let metrics = fs::read_to_string(metrics).expect("failed to read metrics");
+    assert!(metrics.contains(retried), "retry has not been counted: {metrics}");
+}
+
+#[test]
+fn hanging_backend() {
+    let mut harness = Harness::new();
+    harness.start("hang", &[]);
+    harness.send(&["--type=text", "--payload=stuck"]);
+
+    // The message is kept while `matrix-commander-rs` hangs
+    harness.wait_calls(1);
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(harness.messages().len(), 1, "message has been removed");
+    harness.stop();
+
+    // The message is delivered again after a restart
+    harness.start("ok", &[]);
+    let calls = harness.wait_calls(2);
+    harness.wait_drained();
+    assert_eq!(calls[1].text(), "stuck");
+}
+
+#[test]
+fn publication() {
+    let mut harness = Harness::new();
+    let large: Vec<u8> = (0..=250u8).cycle().take(8 * 1024 * 1024).collect();
+    let large = harness.file("large.bin", &large);
+
+    // A half-written tempfile of a crashed client is never picked up
+    let mut stale = File::create(harness.ipc().join("00000000-0000-0000-0000-000000000000.tmp")).expect("tmp");
+    stale.write_all(b"type=plaintext\n\nhalf-wri").expect("failed to write stale tempfile");
+    harness.start("ok", &[("UPLOAD_SIZE_MAX", "16777216")]);
+
+    // Watch the IPC directory and ensure that published messages never change their size
+    let done = Arc::new(AtomicBool::new(false));
+    let watcher = {
+        let (ipc, done) = (harness.ipc(), done.clone());
+        thread::spawn(move || {
+            let mut sizes = BTreeMap::new();
+            while !done.load(Ordering::SeqCst) {
+                for entry in fs::read_dir(&ipc).expect("failed to list IPC directory").flatten() {
+                    let path = entry.path();
+                    let Some("msg") = path.extension().and_then(|ext| ext.to_str()) else {
+                        continue;
+                    };
+                    let Ok(metadata) = fs::metadata(&path) else {
+                        continue;
+                    };
+                    let size = *sizes.entry(path.clone()).or_insert(metadata.len());
+                    assert_eq!(size, metadata.len(), "published message changed its size: {}", path.display());
+                }
+            }
+        })
+    };
+
+    // Send some large messages while the server is polling
+    for _ in 0..3 {
+        harness.send(&["--type=raw", &format!("--payload={}", large.display())]);
+    }
+    let calls = harness.wait_calls(3);
+    harness.wait_drained();
+    done.store(true, Ordering::SeqCst);
+    watcher.join().expect("published message has been observed half-written");
+
+    // All messages have been delivered completely and the stale tempfile is untouched
+    let expected = fs::read(&large).expect("failed to read payload file");
+    assert_eq!(calls.len(), 3, "unexpected calls: {calls:?}");
+    assert!(calls.iter().all(|call| call.stdin == expected), "attachment has been truncated");
+    assert!(harness.ipc().join("00000000-0000-0000-0000-000000000000.tmp").exists());
+}
+
+#[test]
+fn odd_entries() {
+    let mut harness = Harness::new();
+    fs::write(harness.ipc().join("IMAGE.JPG.RAW"), b"uppercase attachment").expect("failed to write entry");
+    fs::write(harness.ipc().join("%2F.raw"), b"invalid attachment name").expect("failed to write entry");
+    fs::write(harness.ipc().join("broken.msg"), b"no header\n\n").expect("failed to write entry");
+    fs::write(harness.ipc().join(".raw"), b"no stem").expect("failed to write entry");
+    fs::write(harness.ipc().join("oversize.txt"), [b'x'; 4097]).expect("failed to write entry");
+    let oversize = [b"type=plaintext\n\n".as_slice(), &[b'x'; 4097]].concat();
+    fs::write(harness.ipc().join("oversize.msg"), oversize).expect("failed to write entry");
+    harness.start("ok", &[]);
+
+    // Uppercase extensions are decoded and invalid entries are quarantined without stopping the server
+    let calls = harness.wait_calls(1);
+    assert_eq!(calls[0].value("--file-name"), Some("IMAGE.JPG"));
+    assert_eq!(calls[0].stdin, b"uppercase attachment");
+    harness.send(&["--type=text", "--payload=still running"]);
+    let calls = harness.wait_calls(2);
+    assert_eq!(calls[1].text(), "still running");
+
+    // Check the quarantine and the ignored entries
+    let quarantine = harness.ipc().join("quarantine");
+    assert!(quarantine.join("%2F.raw").exists(), "invalid attachment name has not been quarantined");
+    assert!(quarantine.join("broken.msg").exists(), "malformed envelope has not been quarantined");
+    assert!(quarantine.join("oversize.txt").exists(), "oversize plaintext message has not been quarantined");
+    assert!(quarantine.join("oversize.msg").exists(), "oversize envelope has not been quarantined");
+    assert!(harness.ipc().join(".raw").exists(), "dotfile has been processed");
+}